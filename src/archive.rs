@@ -0,0 +1,116 @@
+//! Writing raw auction dumps to disk so they can be reprocessed later.
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+/// How archived dump files are named, relative to the archive directory.
+#[derive(Debug, Clone)]
+pub enum NamingScheme {
+    /// `{slug}_{timestamp}.json`
+    SlugTimestamp,
+    /// `{date}/{slug}.json`
+    DateSlug,
+    /// A custom template. `{slug}`, `{timestamp}` (Blizzard's lastModified, in
+    /// milliseconds) and `{date}` (`YYYY-MM-DD`, UTC) are substituted. Slashes
+    /// make subdirectories.
+    Template(String),
+}
+
+impl NamingScheme {
+    /// Returns the file name for a dump of `slug` last modified at `last_modified`.
+    pub fn file_name(&self, slug: &str, last_modified: u64) -> String {
+        let template = match *self {
+            NamingScheme::SlugTimestamp => "{slug}_{timestamp}.json",
+            NamingScheme::DateSlug => "{date}/{slug}.json",
+            NamingScheme::Template(ref template) => &template[..],
+        };
+        template.replace("{slug}", slug)
+            .replace("{timestamp}", &last_modified.to_string())
+            .replace("{date}", &utc_date(last_modified))
+    }
+}
+
+/// Where and how raw dumps get archived.
+#[derive(Debug, Clone)]
+pub struct ArchiveConfig {
+    pub dir: PathBuf,
+    pub naming: NamingScheme,
+    /// Gzip archived files, adding `.gz` to their names.
+    pub gzip: bool,
+}
+
+impl ArchiveConfig {
+    /// The path a dump of `slug` last modified at `last_modified` is archived at.
+    pub fn path_for(&self, slug: &str, last_modified: u64) -> PathBuf {
+        let mut file_name = self.naming.file_name(slug, last_modified);
+        if self.gzip {
+            file_name.push_str(".gz");
+        }
+        self.dir.join(file_name)
+    }
+
+    /// Archives a raw dump, creating directories as needed. Returns where it was written.
+    pub fn write_dump(&self, slug: &str, last_modified: u64, body: &[u8]) -> io::Result<PathBuf> {
+        let path = self.path_for(slug, last_modified);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = File::create(&path)?;
+        if self.gzip {
+            let mut encoder = GzEncoder::new(file, Compression::Default);
+            encoder.write_all(body)?;
+            encoder.finish()?;
+        } else {
+            let mut file = file;
+            file.write_all(body)?;
+        }
+        Ok(path)
+    }
+}
+
+/// Formats a millisecond unix timestamp as a `YYYY-MM-DD` UTC date.
+fn utc_date(timestamp_ms: u64) -> String {
+    // Civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (timestamp_ms / 1000 / 86400) as i64 + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use super::utc_date;
+
+    #[test]
+    fn test_utc_date() {
+        assert_eq!(utc_date(0), "1970-01-01");
+        assert_eq!(utc_date(1480000000000), "2016-11-24");
+        assert_eq!(utc_date(951782400000), "2000-02-29");
+    }
+
+    #[test]
+    fn test_naming_schemes() {
+        let config = ArchiveConfig {
+            dir: PathBuf::from("/archive"),
+            naming: NamingScheme::DateSlug,
+            gzip: true,
+        };
+        assert_eq!(config.path_for("earthen-ring", 1480000000000), PathBuf::from("/archive/2016-11-24/earthen-ring.json.gz"));
+        assert_eq!(NamingScheme::SlugTimestamp.file_name("earthen-ring", 1480000000000),
+                   "earthen-ring_1480000000000.json");
+        assert_eq!(NamingScheme::Template("{slug}/{date}-{timestamp}.json".to_owned()).file_name("a", 0),
+                   "a/1970-01-01-0.json");
+    }
+}
@@ -0,0 +1,208 @@
+//! A fast path for decoding auction data files.
+//! Dumps for big realms run to tens of megabytes, and `json::decode` first builds
+//! the entire document as a `Json` tree before turning it into structs. Instead,
+//! this walks rustc_serialize's streaming parser and builds `AuctionListing`s
+//! directly, skipping the fields we don't keep (`owner`, `rand`, etc.) without
+//! ever materializing them.
+//!
+//! Without the `rustc-serialize` feature there's no streaming parser, so dumps
+//! are decoded with serde, which also builds structs directly.
+#[cfg(feature = "rustc-serialize")]
+use rustc_serialize::json::{DecoderError, JsonEvent, Parser, StackElement};
+#[cfg(not(feature = "rustc-serialize"))]
+use serde_json;
+
+#[cfg(feature = "rustc-serialize")]
+use battle_net_api_client::{BonusList, ItemModifier};
+use battle_net_api_client::{AuctionListing, JsonError};
+
+/// Rough size in bytes of one listing in a dump, used to pre-size the listings vec.
+#[cfg(feature = "rustc-serialize")]
+const ESTIMATED_BYTES_PER_LISTING: usize = 180;
+
+/// Decodes the `auctions` array of an auction data file.
+#[cfg(feature = "rustc-serialize")]
+pub fn decode_auction_listings(body: &str) -> Result<Vec<AuctionListing>, JsonError> {
+    let mut parser = Parser::new(body.chars());
+    let mut listings = Vec::with_capacity(body.len() / ESTIMATED_BYTES_PER_LISTING);
+    // How deeply nested in arrays and objects we currently are.
+    let mut depth = 0;
+    // The depth of the auctions array, while we're inside it.
+    let mut auctions_depth: Option<u32> = None;
+    let mut found_auctions = false;
+    let mut current: Option<AuctionListing> = None;
+
+    while let Some(event) = parser.next() {
+        let in_listing = auctions_depth.map_or(false, |d| depth == d + 1);
+        // Directly inside one of the objects in a listing's bonusLists or modifiers.
+        let in_listing_entry = auctions_depth.map_or(false, |d| depth == d + 3);
+        match event {
+            JsonEvent::Error(e) => return Err(DecoderError::ParseError(e)),
+            JsonEvent::ArrayStart => {
+                if in_listing {
+                    if let (Some(listing), Some(StackElement::Key(key))) = (current.as_mut(), parser.stack().top()) {
+                        match key {
+                            "bonusLists" => listing.bonusLists = Some(Vec::new()),
+                            "modifiers" => listing.modifiers = Some(Vec::new()),
+                            _ => (),
+                        }
+                    }
+                }
+                depth += 1;
+                if depth == 2 && parser.stack().ends_with(&[StackElement::Key("auctions")]) {
+                    auctions_depth = Some(depth);
+                    found_auctions = true;
+                }
+            },
+            JsonEvent::ArrayEnd => {
+                if auctions_depth == Some(depth) {
+                    auctions_depth = None;
+                }
+                depth -= 1;
+            },
+            JsonEvent::ObjectStart => {
+                if auctions_depth == Some(depth) {
+                    current = Some(AuctionListing::default());
+                } else if auctions_depth.map_or(false, |d| depth == d + 2) {
+                    // An entry in one of the listing's arrays.
+                    let stack = parser.stack();
+                    if let Some(listing) = current.as_mut() {
+                        match stack.get(stack.len() - 2) {
+                            StackElement::Key("bonusLists") => if let Some(ref mut bonus_lists) = listing.bonusLists {
+                                bonus_lists.push(BonusList::default());
+                            },
+                            StackElement::Key("modifiers") => if let Some(ref mut modifiers) = listing.modifiers {
+                                modifiers.push(ItemModifier::default());
+                            },
+                            _ => (),
+                        }
+                    }
+                }
+                depth += 1;
+            },
+            JsonEvent::ObjectEnd => {
+                if in_listing {
+                    if let Some(listing) = current.take() {
+                        listings.push(listing);
+                    }
+                }
+                depth -= 1;
+            },
+            JsonEvent::U64Value(value) if in_listing => {
+                if let (Some(listing), Some(StackElement::Key(key))) = (current.as_mut(), parser.stack().top()) {
+                    match key {
+                        "item" => listing.item = value,
+                        "buyout" => listing.buyout = value,
+                        "quantity" => listing.quantity = value,
+                        "unit_price" => listing.unit_price = Some(value),
+                        "bid" => listing.bid = Some(value),
+                        _ => (),
+                    }
+                }
+            },
+            JsonEvent::U64Value(value) if in_listing_entry => {
+                let stack = parser.stack();
+                if let (Some(listing), Some(StackElement::Key(key))) = (current.as_mut(), stack.top()) {
+                    match (stack.get(stack.len() - 3), key) {
+                        (StackElement::Key("bonusLists"), "bonusListId") => {
+                            if let Some(bonus_list) = listing.bonusLists.as_mut().and_then(|b| b.last_mut()) {
+                                bonus_list.bonusListId = value;
+                            }
+                        },
+                        (StackElement::Key("modifiers"), "type") => {
+                            if let Some(modifier) = listing.modifiers.as_mut().and_then(|m| m.last_mut()) {
+                                modifier.modifier_type = value;
+                            }
+                        },
+                        (StackElement::Key("modifiers"), "value") => {
+                            if let Some(modifier) = listing.modifiers.as_mut().and_then(|m| m.last_mut()) {
+                                modifier.value = value;
+                            }
+                        },
+                        _ => (),
+                    }
+                }
+            },
+            JsonEvent::StringValue(value) if in_listing => {
+                if let (Some(listing), Some(StackElement::Key("timeLeft"))) = (current.as_mut(), parser.stack().top()) {
+                    listing.timeLeft = Some(value);
+                }
+            },
+            _ => (),
+        }
+    }
+    if !found_auctions {
+        return Err(DecoderError::MissingFieldError("auctions".to_owned()));
+    }
+    Ok(listings)
+}
+
+#[cfg(not(feature = "rustc-serialize"))]
+#[derive(Deserialize)]
+struct AuctionListingsReply {
+    auctions: Vec<AuctionListing>,
+}
+
+/// Decodes the `auctions` array of an auction data file.
+#[cfg(not(feature = "rustc-serialize"))]
+pub fn decode_auction_listings(body: &str) -> Result<Vec<AuctionListing>, JsonError> {
+    serde_json::from_str::<AuctionListingsReply>(body).map(|reply| reply.auctions)
+}
+
+// These compare against rustc_serialize's own decoding.
+#[cfg(all(test, feature = "rustc-serialize"))]
+mod tests {
+    use rustc_serialize::json;
+    use test::Bencher;
+
+    use battle_net_api_client::AuctionListing;
+
+    use super::*;
+
+    /// What decoding a dump looks like with the plain derive.
+    #[derive(RustcDecodable)]
+    struct NaiveReply {
+        auctions: Vec<AuctionListing>,
+    }
+
+    fn sample_dump(listings: usize) -> String {
+        let auctions: Vec<String> = (0..listings).map(|i| {
+            format!("{{\"auc\":{},\"item\":124124,\"owner\":\"Sqrl\",\"ownerRealm\":\"EarthenRing\",\
+                     \"bid\":{},\"buyout\":{},\"quantity\":{},\"timeLeft\":\"LONG\",\"rand\":-5,\"seed\":0,\
+                     \"context\":0,\"bonusLists\":[{{\"bonusListId\":1}},{{\"bonusListId\":{}}}],\
+                     \"modifiers\":[{{\"type\":9,\"value\":110}}]}}", i, i * 90, i * 100, i % 20 + 1, i)
+        }).collect();
+        format!("{{\"realms\":[{{\"name\":\"Earthen Ring\",\"slug\":\"earthen-ring\"}}],\"auctions\":[{}]}}",
+                auctions.join(","))
+    }
+
+    #[test]
+    fn test_matches_naive_decode() {
+        let dump = sample_dump(50);
+        let fast = decode_auction_listings(&dump).unwrap();
+        let naive: NaiveReply = json::decode(&dump).unwrap();
+        assert_eq!(fast.len(), naive.auctions.len());
+        for (f, n) in fast.iter().zip(naive.auctions.iter()) {
+            assert_eq!((f.item, f.buyout, f.quantity, f.bid, &f.timeLeft), (n.item, n.buyout, n.quantity, n.bid, &n.timeLeft));
+            assert_eq!((&f.bonusLists, &f.modifiers), (&n.bonusLists, &n.modifiers));
+        }
+    }
+
+    #[test]
+    fn test_missing_auctions() {
+        assert!(decode_auction_listings("{\"realms\":[]}").is_err());
+        assert!(decode_auction_listings("{\"auctions\":[").is_err());
+    }
+
+    #[bench]
+    fn bench_fast_decode(b: &mut Bencher) {
+        let dump = sample_dump(10_000);
+        b.iter(|| decode_auction_listings(&dump).unwrap());
+    }
+
+    #[bench]
+    fn bench_naive_decode(b: &mut Bencher) {
+        let dump = sample_dump(10_000);
+        b.iter(|| json::decode::<NaiveReply>(&dump).unwrap());
+    }
+}
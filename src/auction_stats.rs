@@ -0,0 +1,926 @@
+//! Helpers for pulling prices out of a realm's auction listings.
+//! All prices here are per-unit and in copper unless noted otherwise.
+use std::cmp::{self, Ordering};
+use std::collections::BTreeMap;
+use std::u64;
+use std::vec;
+
+use battle_net_api_client::{AuctionListing, ItemInfo};
+
+/// How much to undercut the cheapest competing listing by.
+#[derive(Debug, Clone, Copy)]
+pub enum Undercut {
+    /// Post at the same price as the cheapest listing.
+    Match,
+    /// Post this many copper below the cheapest listing.
+    Copper(u64),
+    /// Post this percentage (e.g. `5.0` for 5%) below the cheapest listing.
+    Percent(f64),
+}
+
+/// A seller's strategy for picking a posting price.
+#[derive(Debug, Clone, Copy)]
+pub struct UndercutPolicy {
+    pub undercut: Undercut,
+    /// We never suggest posting below this per-unit price. Usually the
+    /// vendor sell price, since selling to a vendor would be better.
+    pub floor: u64,
+}
+
+/// Whether a listing has units to buy and a buyout to buy them at. Every per-unit
+/// price helper skips listings that don't, so a stray `quantity: 0` can't end up
+/// as the min buyout or in a division.
+fn is_buyable(listing: &AuctionListing) -> bool {
+    listing.quantity > 0 && listing.unit_price() > 0
+}
+
+/// Returns the per-unit buyouts for all listings of `item_id` that have a buyout.
+fn unit_buyouts(listings: &[AuctionListing], item_id: u64) -> Vec<u64> {
+    listings.iter()
+        .filter(|listing| listing.item == item_id && is_buyable(listing))
+        .map(|listing| listing.unit_price())
+        .collect()
+}
+
+/// Filters out listings the stats can't use, up front: zero-quantity listings
+/// always, and listings without a buyout unless `bid_mode` is set (in which case
+/// they're kept if they have a bid). Returns the listings kept and how many were
+/// dropped.
+pub fn sanitize_listings(listings: Vec<AuctionListing>, bid_mode: bool) -> (Vec<AuctionListing>, usize) {
+    let total = listings.len();
+    let kept: Vec<AuctionListing> = listings.into_iter().filter(|listing| {
+        listing.quantity > 0 && (listing.total_buyout() > 0 || (bid_mode && listing.bid.map_or(false, |bid| bid > 0)))
+    }).collect();
+    let dropped = total - kept.len();
+    (kept, dropped)
+}
+
+/// Returns the ids of every item listed, sorted and without duplicates.
+pub fn distinct_item_ids(listings: &[AuctionListing]) -> Vec<u64> {
+    let mut ids: Vec<u64> = listings.iter().map(|listing| listing.item).collect();
+    ids.sort();
+    ids.dedup();
+    ids
+}
+
+/// Returns the lowest per-unit buyout for an item, or None if nobody has
+/// it listed with a buyout.
+pub fn min_buyout(listings: &[AuctionListing], item_id: u64) -> Option<u64> {
+    unit_buyouts(listings, item_id).into_iter().min()
+}
+
+/// Like `min_buyout` but ignores listings of fewer than `min_qty` units, so a
+/// single-unit troll posting doesn't set the floor for something normally sold
+/// in big stacks.
+pub fn min_buyout_min_qty(listings: &[AuctionListing], item_id: u64, min_qty: u64) -> Option<u64> {
+    listings.iter()
+        .filter(|listing| listing.item == item_id && listing.quantity >= min_qty && is_buyable(listing))
+        .map(|listing| listing.unit_price())
+        .min()
+}
+
+/// Suggests a per-unit price to post `item_id` at by undercutting the current
+/// lowest buyout according to `policy`. Returns None if there's nothing to undercut.
+pub fn suggest_post_price(listings: &[AuctionListing], item_id: u64, policy: UndercutPolicy) -> Option<u64> {
+    min_buyout(listings, item_id).map(|lowest| {
+        let suggested = match policy.undercut {
+            Undercut::Match => lowest,
+            Undercut::Copper(copper) => lowest.saturating_sub(copper),
+            Undercut::Percent(percent) => (lowest as f64 * (1.0 - percent / 100.0)) as u64,
+        };
+        if suggested < policy.floor { policy.floor } else { suggested }
+    })
+}
+
+/// Sums buyouts in a wider type so a whole realm can't overflow, saturating
+/// at `u64::MAX` on the way out.
+fn saturating_total<'a, I: Iterator<Item=&'a AuctionListing>>(listings: I) -> u64 {
+    let total = listings.fold(0u128, |sum, listing| sum + listing.total_buyout() as u128);
+    if total > u64::MAX as u128 { u64::MAX } else { total as u64 }
+}
+
+/// The quantity-weighted average per-unit buyout of `listings`, i.e. what buying
+/// all of them would cost per unit. Listings without a buyout are skipped.
+/// Returns None if there's nothing to average.
+fn average_unit_price<'a, I: Iterator<Item=&'a AuctionListing>>(listings: I) -> Option<u64> {
+    let (total, quantity) = listings
+        .filter(|listing| is_buyable(listing))
+        .fold((0u128, 0u128), |(total, quantity), listing| {
+            (total + listing.total_buyout() as u128, quantity + listing.quantity as u128)
+        });
+    if quantity == 0 { None } else { Some((total / quantity) as u64) }
+}
+
+/// Returns the quantity-weighted average per-unit buyout of every item, i.e.
+/// `sum(buyout) / sum(quantity)` over its listings with a buyout. Items with no
+/// buyable units are left out.
+pub fn weighted_average_prices(listings: &[AuctionListing]) -> BTreeMap<u64, u64> {
+    let mut by_item: BTreeMap<u64, Vec<&AuctionListing>> = BTreeMap::new();
+    for listing in listings {
+        by_item.entry(listing.item).or_insert(Vec::new()).push(listing);
+    }
+    by_item.into_iter().filter_map(|(item, item_listings)| {
+        average_unit_price(item_listings.into_iter()).map(|average| (item, average))
+    }).collect()
+}
+
+/// Counts how many listings of `item_id` have a per-unit buyout at or below `price`,
+/// i.e. how many sellers you'd have to undercut to be the cheapest at `price`.
+pub fn competition_at_or_below(listings: &[AuctionListing], item_id: u64, price: u64) -> usize {
+    unit_buyouts(listings, item_id).into_iter().filter(|&unit_price| unit_price <= price).count()
+}
+
+/// How far an item's cheapest listing sits below its market value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Spread {
+    pub min_buyout: u64,
+    /// The quantity-weighted average per-unit buyout.
+    pub market_value: u64,
+    /// How far below the market value the min buyout is, as a percentage of
+    /// the market value. A big spread means someone posted cheap.
+    pub spread_pct: f64,
+}
+
+/// Compares the cheapest per-unit buyout of `item_id` with its market value.
+/// Returns None if nobody has it listed with a buyout.
+pub fn price_spread(listings: &[AuctionListing], item_id: u64) -> Option<Spread> {
+    let min = match min_buyout(listings, item_id) {
+        Some(min) => min,
+        None => return None,
+    };
+    let market_value = match average_unit_price(listings.iter().filter(|listing| listing.item == item_id)) {
+        Some(market_value) => market_value,
+        None => return None,
+    };
+    Some(Spread {
+        min_buyout: min,
+        market_value: market_value,
+        spread_pct: (market_value - min) as f64 / market_value as f64 * 100.0,
+    })
+}
+
+/// What makes two listings the same thing for pricing purposes: the item plus the
+/// bonuses and modifiers that change its item level, sockets and so on. Two
+/// listings of a piece of gear are only comparable if their signatures match.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ItemSignature {
+    pub item: u64,
+    /// Sorted bonus list ids.
+    pub bonus_lists: Vec<u64>,
+    /// Sorted `(type, value)` modifier pairs.
+    pub modifiers: Vec<(u64, u64)>,
+}
+
+impl ItemSignature {
+    pub fn of(listing: &AuctionListing) -> ItemSignature {
+        let mut bonus_lists: Vec<u64> = listing.bonusLists.iter()
+            .flat_map(|bonus_lists| bonus_lists.iter().map(|bonus_list| bonus_list.bonusListId))
+            .collect();
+        bonus_lists.sort();
+        let mut modifiers: Vec<(u64, u64)> = listing.modifiers.iter()
+            .flat_map(|modifiers| modifiers.iter().map(|modifier| (modifier.modifier_type, modifier.value)))
+            .collect();
+        modifiers.sort();
+        ItemSignature {
+            item: listing.item,
+            bonus_lists: bonus_lists,
+            modifiers: modifiers,
+        }
+    }
+}
+
+/// Groups listings by `ItemSignature`, so that prices can be computed per variant
+/// of a piece of gear rather than lumping every item level together.
+pub fn group_by_signature(listings: Vec<AuctionListing>) -> BTreeMap<ItemSignature, Vec<AuctionListing>> {
+    let mut groups: BTreeMap<ItemSignature, Vec<AuctionListing>> = BTreeMap::new();
+    for listing in listings {
+        groups.entry(ItemSignature::of(&listing)).or_insert(Vec::new()).push(listing);
+    }
+    groups
+}
+
+/// Returns the listings of `item_id` that can be bought out, cheapest per unit first.
+/// Listings at the same per-unit price are ordered by quantity, largest stack first.
+pub fn cheapest_first(listings: Vec<AuctionListing>, item_id: u64) -> Vec<AuctionListing> {
+    let mut listings: Vec<AuctionListing> = listings.into_iter()
+        .filter(|listing| listing.item == item_id && is_buyable(listing))
+        .collect();
+    listings.sort_by(|a, b| match a.unit_price().cmp(&b.unit_price()) {
+        Ordering::Equal => b.quantity.cmp(&a.quantity),
+        ordering => ordering,
+    });
+    listings
+}
+
+/// Iterates over the listings of `item_id` with a per-unit buyout between `min_unit`
+/// and `max_unit` inclusive, cheapest per unit first, without copying them. Ties are
+/// ordered like `cheapest_first`. Chain `filter` for anything more specific, e.g.
+/// `.filter(|listing| listing.quantity > 1)` to skip single-unit troll postings.
+pub fn listings_in_price_range<'a>(listings: &'a [AuctionListing], item_id: u64, min_unit: u64, max_unit: u64)
+        -> vec::IntoIter<&'a AuctionListing> {
+    let mut matching: Vec<&AuctionListing> = listings.iter()
+        .filter(|listing| listing.item == item_id && is_buyable(listing))
+        .filter(|listing| min_unit <= listing.unit_price() && listing.unit_price() <= max_unit)
+        .collect();
+    matching.sort_by(|a, b| match a.unit_price().cmp(&b.unit_price()) {
+        Ordering::Equal => b.quantity.cmp(&a.quantity),
+        ordering => ordering,
+    });
+    matching.into_iter()
+}
+
+/// How one of my posted prices compares with the market.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UndercutEntry {
+    pub item_id: u64,
+    /// The per-unit price I posted at.
+    pub my_price: u64,
+    /// The current `min_buyout`, or None if nothing is listed with a buyout.
+    pub market_min: Option<u64>,
+    /// How far the market min is below my price. 0 if I'm not undercut.
+    pub undercut_by: u64,
+}
+
+impl UndercutEntry {
+    pub fn is_undercut(&self) -> bool {
+        self.undercut_by > 0
+    }
+}
+
+/// Checks my posted per-unit prices, as (item id, price) pairs, against the current
+/// listings, e.g. to decide what to repost. Entries come back in the order given.
+/// Owners aren't known so my own auctions count towards the market min, which
+/// means matching my price doesn't count as being undercut.
+pub fn undercut_report(my_posts: &[(u64, u64)], listings: &[AuctionListing]) -> Vec<UndercutEntry> {
+    my_posts.iter().map(|&(item_id, my_price)| {
+        let market_min = min_buyout(listings, item_id);
+        UndercutEntry {
+            item_id: item_id,
+            my_price: my_price,
+            market_min: market_min,
+            undercut_by: market_min.map_or(0, |min| my_price.saturating_sub(min)),
+        }
+    }).collect()
+}
+
+/// Returns the summed buyout of every listing, in copper. Listings without a
+/// buyout contribute nothing.
+pub fn total_market_value(listings: &[AuctionListing]) -> u64 {
+    saturating_total(listings.iter())
+}
+
+/// Like `total_market_value` but grouped by item class. `class_of` maps an item
+/// id to its class id.
+pub fn total_market_value_by_class<F: Fn(u64) -> u64>(listings: &[AuctionListing], class_of: F) -> BTreeMap<u64, u64> {
+    let mut by_class: BTreeMap<u64, Vec<&AuctionListing>> = BTreeMap::new();
+    for listing in listings {
+        by_class.entry(class_of(listing.item)).or_insert(Vec::new()).push(listing);
+    }
+    by_class.into_iter().map(|(class, class_listings)| {
+        (class, saturating_total(class_listings.into_iter()))
+    }).collect()
+}
+
+/// Partitions listings by item class. `class_of` maps an item id to its class id.
+pub fn shard_by_class<F: Fn(u64) -> u64>(listings: Vec<AuctionListing>, class_of: F) -> BTreeMap<u64, Vec<AuctionListing>> {
+    let mut shards: BTreeMap<u64, Vec<AuctionListing>> = BTreeMap::new();
+    for listing in listings {
+        shards.entry(class_of(listing.item)).or_insert(Vec::new()).push(listing);
+    }
+    shards
+}
+
+/// Item classes whose items stack and sell interchangeably by the unit: consumables
+/// (0), gems (3), reagents (5), trade goods (7), item enhancements (8) and glyphs (16).
+pub const COMMODITY_CLASSES: [u64; 6] = [0, 3, 5, 7, 8, 16];
+
+/// Whether `item_id` is a commodity, i.e. belongs to one of `COMMODITY_CLASSES`.
+/// `class_of` maps an item id to its class id.
+pub fn is_commodity_item<F: Fn(u64) -> u64>(item_id: u64, class_of: F) -> bool {
+    COMMODITY_CLASSES.contains(&class_of(item_id))
+}
+
+/// Splits listings into commodities and everything else (gear, pets, ...), so
+/// commodities can be priced per unit and the rest by `ItemSignature`. Returns
+/// (commodities, others). `class_of` maps an item id to its class id.
+pub fn partition_commodities<F: Fn(u64) -> u64>(listings: Vec<AuctionListing>, class_of: F)
+        -> (Vec<AuctionListing>, Vec<AuctionListing>) {
+    listings.into_iter().partition(|listing| is_commodity_item(listing.item, &class_of))
+}
+
+/// How busy a realm's auction house is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ActivityScore {
+    pub distinct_items: usize,
+    pub total_listings: usize,
+    /// As returned by `total_market_value`.
+    pub total_market_value: u64,
+    /// `distinct_items * total_listings`: a single number to rank realms by. Realms
+    /// with lots of listings across lots of items score highest.
+    pub composite: f64,
+}
+
+/// Scores how active a realm's market is from its listings, for deciding which
+/// realms are worth scraping first.
+pub fn realm_activity_score(listings: &[AuctionListing]) -> ActivityScore {
+    let distinct_items = distinct_item_ids(listings).len();
+    ActivityScore {
+        distinct_items: distinct_items,
+        total_listings: listings.len(),
+        total_market_value: total_market_value(listings),
+        composite: distinct_items as f64 * listings.len() as f64,
+    }
+}
+
+/// Roughly estimates the chance that a unit of `item_id` posted now sells within a
+/// period, given `velocity`: the number of units observed selling per period.
+///
+/// The model treats the auction house as a queue. A newly posted unit has to wait
+/// for everything already listed (the depth) to sell before it does, so with
+/// `depth` units listed it sells within the period with probability
+/// `velocity / (depth + 1)`, capped at 1.0. That's pessimistic for posts that
+/// undercut, and ignores cancellations and expiry, so use it as a sorting key
+/// rather than a real probability.
+pub fn sale_probability(item_id: u64, listings: &[AuctionListing], velocity: f64) -> f64 {
+    if velocity <= 0.0 {
+        return 0.0;
+    }
+    let depth: u64 = listings.iter()
+        .filter(|listing| listing.item == item_id)
+        .fold(0, |sum, listing| sum + listing.quantity);
+    (velocity / (depth + 1) as f64).min(1.0)
+}
+
+/// How many units of an item to post so that, together with what's already listed,
+/// supply roughly matches what should sell in the next `target_days`. `velocity` is
+/// units sold per day (e.g. from `TurnoverTracker::quantity_sold` over a few days,
+/// divided by the days) and `current_depth` the units already listed.
+///
+/// Same queue model as `sale_probability`: the `current_depth` units already listed
+/// sell first, so we post whatever demand is left over, `velocity * target_days -
+/// current_depth` rounded down, or nothing if the market already has enough.
+/// Posting more than that would only lengthen the queue and push the price down.
+/// `item_id` is the item the numbers are for, which keeps calls in line with
+/// `sale_probability`'s; the result doesn't depend on it.
+pub fn suggested_post_quantity(_item_id: u64, velocity: f64, current_depth: u64, target_days: f64) -> u64 {
+    let demand = velocity * target_days;
+    if demand <= current_depth as f64 { 0 } else { (demand - current_depth as f64) as u64 }
+}
+
+/// Finds listings priced far below their item's usual price. `reference_prices` maps
+/// item ids to a typical per-unit price, and a listing is returned if its per-unit
+/// buyout is below `reference * (1 - threshold_pct / 100)`. Items without a
+/// reference price are ignored.
+pub fn find_snipes(listings: &[AuctionListing], reference_prices: &BTreeMap<u64, u64>, threshold_pct: f64) -> Vec<AuctionListing> {
+    listings.iter().filter(|listing| {
+        let unit_price = listing.unit_price();
+        match reference_prices.get(&listing.item) {
+            Some(&reference) if is_buyable(listing) =>
+                (unit_price as f64) < reference as f64 * (1.0 - threshold_pct / 100.0),
+            _ => false,
+        }
+    }).cloned().collect()
+}
+
+/// Listings priced per unit above what a vendor sells their item for, which are
+/// never worth buying from the auction house. Items in `items` without a
+/// `purchase_price`, and listings of items not in `items`, are left out.
+pub fn above_vendor_price(listings: &[AuctionListing], items: &[ItemInfo]) -> Vec<AuctionListing> {
+    let vendor_prices: BTreeMap<u64, u64> = items.iter()
+        .filter_map(|item| item.purchase_price.map(|price| (item.id, price)))
+        .collect();
+    listings.iter().filter(|listing| match vendor_prices.get(&listing.item) {
+        Some(&vendor_price) => is_buyable(listing) && listing.unit_price() > vendor_price,
+        None => false,
+    }).cloned().collect()
+}
+
+/// An item's prices across a set of realms.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlobalItemStat {
+    /// The lowest per-unit buyout on any realm.
+    pub global_min: u64,
+    /// The realm `global_min` is on. If several realms tie, the first one given.
+    pub cheapest_realm: String,
+    /// The median of each realm's `min_buyout`, over the realms that have the item
+    /// listed. With an even number of realms it's the mean of the middle two.
+    pub median_across_realms: u64,
+}
+
+/// Aggregates per-realm listings, e.g. from `get_auction_listings_for`, into
+/// per-item stats across all the realms: where each item is cheapest and what it
+/// typically costs. Items nobody has listed with a buyout are left out.
+pub fn global_item_stats(per_realm: &[(String, Vec<AuctionListing>)]) -> BTreeMap<u64, GlobalItemStat> {
+    let mut mins_by_item: BTreeMap<u64, Vec<(u64, &str)>> = BTreeMap::new();
+    for &(ref realm, ref listings) in per_realm {
+        for item_id in distinct_item_ids(listings) {
+            if let Some(min) = min_buyout(listings, item_id) {
+                mins_by_item.entry(item_id).or_insert(Vec::new()).push((min, &realm[..]));
+            }
+        }
+    }
+    mins_by_item.into_iter().map(|(item_id, realm_mins)| {
+        let &(global_min, cheapest_realm) = realm_mins.iter()
+            .fold(&realm_mins[0], |best, candidate| if candidate.0 < best.0 { candidate } else { best });
+        let mut mins: Vec<u64> = realm_mins.iter().map(|&(min, _)| min).collect();
+        mins.sort();
+        let middle = mins.len() / 2;
+        let median = if mins.len() % 2 == 0 {
+            ((mins[middle - 1] as u128 + mins[middle] as u128) / 2) as u64
+        } else {
+            mins[middle]
+        };
+        (item_id, GlobalItemStat {
+            global_min: global_min,
+            cheapest_realm: cheapest_realm.to_owned(),
+            median_across_realms: median,
+        })
+    }).collect()
+}
+
+/// What buying up to some quantity of an item would take.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Acquisition {
+    /// Total copper spent.
+    pub cost: u64,
+    /// Units bought. Less than asked for if there weren't enough listed, and can
+    /// be more since stacks have to be bought whole.
+    pub quantity: u64,
+}
+
+impl Acquisition {
+    pub fn is_complete(&self, wanted: u64) -> bool {
+        self.quantity >= wanted
+    }
+}
+
+/// The cheapest way to buy `quantity` units of `item_id`, cheapest per unit first.
+/// Commodities can be bought a few units at a time, but other listings only as a
+/// whole stack.
+pub fn cost_to_acquire(listings: &[AuctionListing], item_id: u64, quantity: u64) -> Acquisition {
+    let mut acquisition = Acquisition { cost: 0, quantity: 0 };
+    for listing in cheapest_first(listings.iter().filter(|listing| listing.item == item_id).cloned().collect(), item_id) {
+        if acquisition.quantity >= quantity {
+            break;
+        }
+        let units = match listing.unit_price {
+            Some(_) => cmp::min(listing.quantity, quantity - acquisition.quantity),
+            None => listing.quantity,
+        };
+        let cost = match listing.unit_price {
+            Some(unit_price) => unit_price.saturating_mul(units),
+            None => listing.total_buyout(),
+        };
+        acquisition.cost = acquisition.cost.saturating_add(cost);
+        acquisition.quantity += units;
+    }
+    acquisition
+}
+
+/// What buying a whole basket on one realm would cost.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasketQuote {
+    pub realm: String,
+    /// Copper to buy everything the realm has towards the basket.
+    pub total_cost: u64,
+    /// Units the realm is short of, summed over the basket's items. 0 if the realm
+    /// can supply the whole basket.
+    pub missing: u64,
+}
+
+impl BasketQuote {
+    pub fn is_complete(&self) -> bool {
+        self.missing == 0
+    }
+}
+
+/// Prices `basket` (item id -> quantity wanted) on every realm, in the order given.
+pub fn basket_quotes(basket: &BTreeMap<u64, u64>, per_realm: &[(String, Vec<AuctionListing>)]) -> Vec<BasketQuote> {
+    per_realm.iter().map(|&(ref realm, ref listings)| {
+        let mut quote = BasketQuote { realm: realm.clone(), total_cost: 0, missing: 0 };
+        for (&item_id, &wanted) in basket {
+            let acquisition = cost_to_acquire(listings, item_id, wanted);
+            quote.total_cost = quote.total_cost.saturating_add(acquisition.cost);
+            quote.missing += wanted.saturating_sub(acquisition.quantity);
+        }
+        quote
+    }).collect()
+}
+
+/// Finds the realm where `basket` (item id -> quantity wanted) is cheapest to buy,
+/// returning it with the total cost in copper. Realms that can't supply the whole
+/// basket only count if none can, in which case the one missing the fewest units
+/// wins. Use `basket_quotes` to see which realms are incomplete. Ties go to the
+/// first realm given. Returns None if there are no realms.
+pub fn cheapest_realm_for_basket(basket: &BTreeMap<u64, u64>, per_realm: &[(String, Vec<AuctionListing>)])
+        -> Option<(String, u64)> {
+    basket_quotes(basket, per_realm).into_iter()
+        .fold(None, |best: Option<BasketQuote>, quote| match best {
+            Some(best) => if (quote.missing, quote.total_cost) < (best.missing, best.total_cost) { Some(quote) } else { Some(best) },
+            None => Some(quote),
+        })
+        .map(|quote| (quote.realm, quote.total_cost))
+}
+
+/// A listing priced below its item's market value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Deal {
+    pub item_id: u64,
+    pub unit_price: u64,
+    pub reference_price: u64,
+    pub quantity: u64,
+    /// `(reference_price - unit_price) * quantity`: what buying the whole listing
+    /// saves compared with paying the reference price.
+    pub total_savings: u64,
+}
+
+/// The `limit` listings that would save the most to buy out compared with
+/// `reference` (item id -> per-unit market value, e.g. from
+/// `weighted_average_prices`), biggest total savings first. Only listings below
+/// their reference count; items without a reference price are ignored.
+pub fn best_deals(listings: &[AuctionListing], reference: &BTreeMap<u64, u64>, limit: usize) -> Vec<Deal> {
+    let mut deals: Vec<Deal> = listings.iter().filter(|listing| is_buyable(listing)).filter_map(|listing| {
+        let unit_price = listing.unit_price();
+        match reference.get(&listing.item) {
+            Some(&reference_price) if unit_price < reference_price => Some(Deal {
+                item_id: listing.item,
+                unit_price: unit_price,
+                reference_price: reference_price,
+                quantity: listing.quantity,
+                total_savings: (reference_price - unit_price).saturating_mul(listing.quantity),
+            }),
+            _ => None,
+        }
+    }).collect();
+    deals.sort_by(|a, b| b.total_savings.cmp(&a.total_savings));
+    deals.truncate(limit);
+    deals
+}
+
+/// The auction house's cut of a sale.
+pub const AH_CUT: f64 = 0.05;
+
+/// Profit per unit of buying at `buy_unit` and reselling at `sell_unit` once the
+/// auction house takes `ah_cut` (usually `AH_CUT`) of the sale, or None if the flip
+/// doesn't make money after the cut.
+pub fn flip_breakeven(buy_unit: u64, sell_unit: u64, ah_cut: f64) -> Option<f64> {
+    let profit = sell_unit as f64 * (1.0 - ah_cut) - buy_unit as f64;
+    if profit > 0.0 { Some(profit) } else { None }
+}
+
+/// Each deal with its per-unit profit after the cut if bought and resold at its
+/// reference price (see `flip_breakeven`). Deals that only look good before the
+/// cut get None.
+pub fn flip_profits(deals: &[Deal], ah_cut: f64) -> Vec<(Deal, Option<f64>)> {
+    deals.iter().map(|&deal| (deal, flip_breakeven(deal.unit_price, deal.reference_price, ah_cut))).collect()
+}
+
+/// The share of an item's posted quantity a single price has to hold to count as
+/// a wall in `detect_price_walls`.
+pub const PRICE_WALL_SHARE: f64 = 0.25;
+
+/// A per-unit price at which a large part of an item's supply is posted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PriceWall {
+    pub unit_price: u64,
+    /// The total quantity posted at exactly `unit_price`.
+    pub quantity: u64,
+    /// `quantity` as a fraction of all the item's posted quantity.
+    pub share: f64,
+}
+
+/// The per-unit prices holding at least `PRICE_WALL_SHARE` of an item's total
+/// quantity, cheapest first. Walls are what someone defending a price posts, so an
+/// item listed at only one price has none: there's nothing to stand out from.
+pub fn detect_price_walls(listings: &[AuctionListing], item_id: u64) -> Vec<PriceWall> {
+    let mut by_price: BTreeMap<u64, u64> = BTreeMap::new();
+    for listing in listings.iter().filter(|listing| listing.item == item_id && is_buyable(listing)) {
+        *by_price.entry(listing.unit_price()).or_insert(0) += listing.quantity;
+    }
+    if by_price.len() < 2 {
+        return Vec::new();
+    }
+    let total = by_price.values().fold(0u64, |total, &quantity| total.saturating_add(quantity));
+    by_price.into_iter()
+        .map(|(unit_price, quantity)| PriceWall {
+            unit_price: unit_price,
+            quantity: quantity,
+            share: quantity as f64 / total as f64,
+        })
+        .filter(|wall| wall.share >= PRICE_WALL_SHARE)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::u64;
+
+    use battle_net_api_client::{AuctionListing, BonusList, ItemInfo, ItemModifier};
+
+    use super::*;
+
+    fn listing(item: u64, buyout: u64, quantity: u64) -> AuctionListing {
+        AuctionListing { item: item, buyout: buyout, quantity: quantity, ..Default::default() }
+    }
+
+    fn commodity(item: u64, unit_price: u64, quantity: u64) -> AuctionListing {
+        AuctionListing { item: item, quantity: quantity, unit_price: Some(unit_price), ..Default::default() }
+    }
+
+    #[test]
+    fn test_min_buyout_is_per_unit() {
+        let listings = vec![listing(1, 1000, 10), listing(1, 150, 1), listing(2, 10, 1), listing(1, 0, 1)];
+        assert_eq!(min_buyout(&listings, 1), Some(100));
+        assert_eq!(min_buyout(&listings, 3), None);
+    }
+
+    #[test]
+    fn test_suggest_post_price() {
+        let listings = vec![listing(1, 2000, 20), listing(1, 500, 2)];
+        let policy = |undercut| UndercutPolicy { undercut: undercut, floor: 0 };
+        assert_eq!(suggest_post_price(&listings, 1, policy(Undercut::Match)), Some(100));
+        assert_eq!(suggest_post_price(&listings, 1, policy(Undercut::Copper(1))), Some(99));
+        assert_eq!(suggest_post_price(&listings, 1, policy(Undercut::Percent(5.0))), Some(95));
+        assert_eq!(suggest_post_price(&listings, 2, policy(Undercut::Match)), None);
+    }
+
+    #[test]
+    fn test_suggest_post_price_respects_floor() {
+        let listings = vec![listing(1, 100, 1)];
+        let policy = UndercutPolicy { undercut: Undercut::Percent(50.0), floor: 80 };
+        assert_eq!(suggest_post_price(&listings, 1, policy), Some(80));
+    }
+
+    #[test]
+    fn test_total_market_value() {
+        let listings = vec![listing(1, 1000, 10), listing(2, 500, 1), listing(3, 0, 1)];
+        assert_eq!(total_market_value(&listings), 1500);
+        let by_class = total_market_value_by_class(&listings, |item| if item == 1 { 7 } else { 9 });
+        assert_eq!(by_class.get(&7), Some(&1000));
+        assert_eq!(by_class.get(&9), Some(&500));
+    }
+
+    #[test]
+    fn test_total_market_value_saturates() {
+        let listings = vec![listing(1, u64::MAX, 1), listing(1, 10, 1)];
+        assert_eq!(total_market_value(&listings), u64::MAX);
+    }
+
+    #[test]
+    fn test_min_buyout_mixes_stacks_and_commodities() {
+        let listings = vec![listing(1, 1000, 10), commodity(1, 90, 200)];
+        assert_eq!(min_buyout(&listings, 1), Some(90));
+        assert_eq!(total_market_value(&listings), 1000 + 90 * 200);
+    }
+
+    #[test]
+    fn test_shard_by_class() {
+        let listings = vec![listing(1, 10, 1), listing(2, 20, 1), listing(3, 30, 1)];
+        let shards = shard_by_class(listings, |item| item % 2);
+        assert_eq!(shards.get(&1).unwrap().iter().map(|l| l.item).collect::<Vec<u64>>(), vec![1, 3]);
+        assert_eq!(shards.get(&0).unwrap().iter().map(|l| l.item).collect::<Vec<u64>>(), vec![2]);
+    }
+
+    #[test]
+    fn test_sale_probability() {
+        let listings = vec![listing(1, 100, 9), listing(2, 100, 100)];
+        assert_eq!(sale_probability(1, &listings, 5.0), 0.5);
+        assert_eq!(sale_probability(1, &listings, 50.0), 1.0);
+        assert_eq!(sale_probability(3, &listings, 0.0), 0.0);
+    }
+
+    #[test]
+    fn test_find_snipes() {
+        let listings = vec![listing(1, 100, 1), listing(1, 690, 10), listing(1, 0, 1), listing(2, 1, 1)];
+        let mut reference_prices = BTreeMap::new();
+        reference_prices.insert(1, 100);
+        let snipes = find_snipes(&listings, &reference_prices, 30.0);
+        assert_eq!(snipes.len(), 1);
+        assert_eq!(snipes[0].buyout, 690);
+    }
+
+    #[test]
+    fn test_competition_at_or_below() {
+        let listings = vec![listing(1, 1000, 10), listing(1, 99, 1), listing(1, 500, 2), listing(1, 0, 1)];
+        assert_eq!(competition_at_or_below(&listings, 1, 100), 2);
+        assert_eq!(competition_at_or_below(&listings, 1, 98), 0);
+        assert_eq!(competition_at_or_below(&listings, 1, 250), 3);
+    }
+
+    #[test]
+    fn test_cheapest_first() {
+        let listings = vec![listing(1, 500, 5), listing(2, 1, 1), listing(1, 90, 1), listing(1, 1000, 10), listing(1, 0, 1)];
+        let sorted = cheapest_first(listings, 1);
+        assert_eq!(sorted.iter().map(|l| (l.unit_price(), l.quantity)).collect::<Vec<(u64, u64)>>(),
+                   vec![(90, 1), (100, 10), (100, 5)]);
+    }
+
+    #[test]
+    fn test_price_spread() {
+        let listings = vec![listing(1, 500, 10), listing(1, 1500, 10), listing(1, 0, 5), listing(2, 10, 1)];
+        let spread = price_spread(&listings, 1).unwrap();
+        assert_eq!((spread.min_buyout, spread.market_value), (50, 100));
+        assert_eq!(spread.spread_pct, 50.0);
+        assert_eq!(price_spread(&listings, 3), None);
+    }
+
+    #[test]
+    fn test_group_by_signature() {
+        let gear = |bonus_ids: &[u64], level: u64| AuctionListing {
+            item: 1,
+            buyout: 100,
+            quantity: 1,
+            bonusLists: Some(bonus_ids.iter().map(|&id| BonusList { bonusListId: id }).collect()),
+            modifiers: Some(vec![ItemModifier { modifier_type: 9, value: level }]),
+            ..Default::default()
+        };
+        let listings = vec![gear(&[2, 1], 110), gear(&[1, 2], 110), gear(&[1], 110), gear(&[1], 100), listing(1, 100, 1)];
+        let groups = group_by_signature(listings);
+        assert_eq!(groups.len(), 4);
+        let signature = ItemSignature { item: 1, bonus_lists: vec![1, 2], modifiers: vec![(9, 110)] };
+        assert_eq!(groups.get(&signature).unwrap().len(), 2);
+        let plain = ItemSignature { item: 1, bonus_lists: vec![], modifiers: vec![] };
+        assert_eq!(groups.get(&plain).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_weighted_average_prices() {
+        let listings = vec![listing(1, 100, 1), listing(1, 900, 3), commodity(2, 50, 10), listing(3, 0, 5),
+                            commodity(4, 10, 0)];
+        let averages = weighted_average_prices(&listings);
+        assert_eq!(averages.get(&1), Some(&250));
+        assert_eq!(averages.get(&2), Some(&50));
+        assert_eq!(averages.get(&3), None);
+        assert_eq!(averages.get(&4), None);
+    }
+
+    #[test]
+    fn test_min_buyout_min_qty() {
+        let listings = vec![listing(1, 10, 1), listing(1, 4000, 20), commodity(1, 150, 50), listing(1, 5, 2)];
+        assert_eq!(min_buyout_min_qty(&listings, 1, 1), Some(2));
+        assert_eq!(min_buyout_min_qty(&listings, 1, 20), Some(150));
+        assert_eq!(min_buyout_min_qty(&listings, 1, 100), None);
+    }
+
+    #[test]
+    fn test_realm_activity_score() {
+        let listings = vec![listing(1, 100, 1), listing(1, 200, 2), listing(2, 50, 1)];
+        let score = realm_activity_score(&listings);
+        assert_eq!((score.distinct_items, score.total_listings, score.total_market_value), (2, 3, 350));
+        assert_eq!(score.composite, 6.0);
+        assert_eq!(realm_activity_score(&[]).composite, 0.0);
+    }
+
+    #[test]
+    fn test_global_item_stats() {
+        let per_realm = vec![
+            ("aegwynn".to_owned(), vec![listing(1, 300, 1), listing(2, 50, 1)]),
+            ("bonechewer".to_owned(), vec![listing(1, 100, 1), listing(3, 0, 1)]),
+            ("zuljin".to_owned(), vec![listing(1, 200, 1), listing(2, 50, 1)]),
+        ];
+        let stats = global_item_stats(&per_realm);
+        assert_eq!(stats.keys().cloned().collect::<Vec<u64>>(), vec![1, 2]);
+        assert_eq!(stats[&1], GlobalItemStat {
+            global_min: 100,
+            cheapest_realm: "bonechewer".to_owned(),
+            median_across_realms: 200,
+        });
+        assert_eq!(stats[&2].cheapest_realm, "aegwynn");
+        assert_eq!(stats[&2].median_across_realms, 50);
+    }
+
+    #[test]
+    fn test_zero_quantity_listings() {
+        let listings = vec![commodity(1, 5, 0), listing(1, 300, 0), listing(1, 100, 2), listing(2, 0, 1),
+                            AuctionListing { item: 2, quantity: 1, bid: Some(50), ..Default::default() }];
+        assert_eq!(min_buyout(&listings, 1), Some(50));
+        assert_eq!(min_buyout_min_qty(&listings, 1, 0), Some(50));
+        assert_eq!(weighted_average_prices(&listings).get(&1), Some(&50));
+        assert_eq!(cheapest_first(listings.clone(), 1).len(), 1);
+
+        let (kept, dropped) = sanitize_listings(listings.clone(), false);
+        assert_eq!((kept.len(), dropped), (1, 4));
+        let (kept, dropped) = sanitize_listings(listings, true);
+        assert_eq!(kept.iter().map(|listing| listing.item).collect::<Vec<u64>>(), vec![1, 2]);
+        assert_eq!(dropped, 3);
+    }
+
+    #[test]
+    fn test_cost_to_acquire() {
+        let listings = vec![commodity(1, 10, 5), commodity(1, 20, 100), listing(2, 100, 10), listing(2, 30, 1)];
+        assert_eq!(cost_to_acquire(&listings, 1, 8), Acquisition { cost: 110, quantity: 8 });
+        assert_eq!(cost_to_acquire(&listings, 2, 2), Acquisition { cost: 100, quantity: 10 });
+        assert_eq!(cost_to_acquire(&listings, 2, 11), Acquisition { cost: 130, quantity: 11 });
+        assert!(!cost_to_acquire(&listings, 1, 200).is_complete(200));
+    }
+
+    #[test]
+    fn test_cheapest_realm_for_basket() {
+        let mut basket = BTreeMap::new();
+        basket.insert(1, 10);
+        basket.insert(2, 1);
+        let per_realm = vec![
+            ("aegwynn".to_owned(), vec![commodity(1, 10, 100), commodity(2, 500, 1)]),
+            ("bonechewer".to_owned(), vec![commodity(1, 20, 100), commodity(2, 100, 1)]),
+            ("zuljin".to_owned(), vec![commodity(1, 1, 100)]),
+        ];
+        assert_eq!(cheapest_realm_for_basket(&basket, &per_realm), Some(("bonechewer".to_owned(), 300)));
+        assert!(!basket_quotes(&basket, &per_realm)[2].is_complete());
+        assert_eq!(cheapest_realm_for_basket(&basket, &per_realm[2..]), Some(("zuljin".to_owned(), 10)));
+        assert_eq!(cheapest_realm_for_basket(&basket, &[]), None);
+    }
+
+    #[test]
+    fn test_partition_commodities() {
+        let class_of = |item_id: u64| if item_id < 100 { 7 } else { 4 };
+        assert!(is_commodity_item(1, &class_of));
+        assert!(!is_commodity_item(100, &class_of));
+        let (commodities, others) = partition_commodities(vec![listing(1, 10, 5), listing(100, 5000, 1), listing(2, 3, 1)],
+                                                          class_of);
+        assert_eq!(commodities.iter().map(|listing| listing.item).collect::<Vec<u64>>(), vec![1, 2]);
+        assert_eq!(others.iter().map(|listing| listing.item).collect::<Vec<u64>>(), vec![100]);
+    }
+
+    #[test]
+    fn test_listings_in_price_range() {
+        let listings = vec![listing(1, 400, 1), listing(1, 1, 1), listing(1, 300, 1), listing(1, 600, 2),
+                            listing(1, 900, 3), listing(2, 300, 1)];
+        let prices: Vec<u64> = listings_in_price_range(&listings, 1, 100, 300).map(|listing| listing.buyout).collect();
+        assert_eq!(prices, vec![900, 600, 300]);
+        assert_eq!(listings_in_price_range(&listings, 1, 0, 500).filter(|listing| listing.quantity > 1).count(), 2);
+        assert_eq!(listings_in_price_range(&listings, 3, 0, u64::MAX).count(), 0);
+    }
+
+    #[test]
+    fn test_undercut_report() {
+        let listings = vec![listing(1, 90, 1), listing(1, 100, 1), listing(2, 500, 1)];
+        let report = undercut_report(&[(1, 100), (2, 500), (3, 10)], &listings);
+        assert_eq!(report[0], UndercutEntry { item_id: 1, my_price: 100, market_min: Some(90), undercut_by: 10 });
+        assert!(report[0].is_undercut());
+        assert!(!report[1].is_undercut());
+        assert_eq!((report[2].market_min, report[2].undercut_by), (None, 0));
+    }
+
+    #[test]
+    fn test_best_deals() {
+        let mut reference = BTreeMap::new();
+        reference.insert(1, 100);
+        reference.insert(2, 1000);
+        let listings = vec![listing(1, 50, 1), listing(1, 400, 10), listing(2, 900, 1), listing(1, 200, 1),
+                            listing(3, 1, 1)];
+        let deals = best_deals(&listings, &reference, 2);
+        assert_eq!(deals.iter().map(|deal| deal.total_savings).collect::<Vec<u64>>(), vec![600, 100]);
+        assert_eq!(deals[0], Deal { item_id: 1, unit_price: 40, reference_price: 100, quantity: 10, total_savings: 600 });
+        assert_eq!(best_deals(&listings, &reference, 10).len(), 3);
+    }
+
+    #[test]
+    fn test_detect_price_walls() {
+        let listings = vec![commodity(1, 90, 5), commodity(1, 95, 5), commodity(1, 100, 200), commodity(1, 100, 100),
+                            commodity(1, 120, 90), commodity(2, 10, 1000)];
+        let walls = detect_price_walls(&listings, 1);
+        assert_eq!(walls.len(), 1);
+        assert_eq!((walls[0].unit_price, walls[0].quantity), (100, 300));
+        assert!((walls[0].share - 0.75).abs() < 1e-9);
+        assert!(detect_price_walls(&listings, 2).is_empty());
+        assert!(detect_price_walls(&listings, 3).is_empty());
+    }
+
+    #[test]
+    fn test_above_vendor_price() {
+        let item = |id: u64, purchase_price: Option<u64>| ItemInfo {
+            id: id,
+            name: String::new(),
+            icon: String::new(),
+            purchase_price: purchase_price,
+            item_class: None,
+            item_subclass: None,
+        };
+        let items = vec![item(1, Some(100)), item(2, None)];
+        let listings = vec![listing(1, 500, 10), listing(1, 1500, 10), listing(2, 1500, 1), listing(3, 1500, 1)];
+        let flagged = above_vendor_price(&listings, &items);
+        assert_eq!(flagged.iter().map(|listing| listing.buyout).collect::<Vec<u64>>(), vec![1500]);
+        assert_eq!(flagged[0].item, 1);
+    }
+
+    #[test]
+    fn test_flip_breakeven() {
+        assert_eq!(flip_breakeven(80, 100, AH_CUT), Some(15.0));
+        assert_eq!(flip_breakeven(96, 100, AH_CUT), None);
+        assert_eq!(flip_breakeven(95, 100, AH_CUT), None);
+        let mut reference = BTreeMap::new();
+        reference.insert(1, 100);
+        let deals = best_deals(&[listing(1, 80, 1), listing(1, 97, 1)], &reference, 10);
+        let profits: Vec<Option<f64>> = flip_profits(&deals, AH_CUT).into_iter().map(|(_, profit)| profit).collect();
+        assert_eq!(profits, vec![Some(15.0), None]);
+    }
+
+    #[test]
+    fn test_suggested_post_quantity() {
+        assert_eq!(suggested_post_quantity(1, 100.0, 150, 2.0), 50);
+        assert_eq!(suggested_post_quantity(1, 10.5, 0, 1.0), 10);
+        assert_eq!(suggested_post_quantity(1, 100.0, 500, 2.0), 0);
+        assert_eq!(suggested_post_quantity(1, 0.0, 0, 7.0), 0);
+    }
+}
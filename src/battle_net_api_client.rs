@@ -6,169 +6,2861 @@
 extern crate hyper;
 extern crate serde_json;
 
-use std::collections::BTreeMap;
-use std::io::Read;
-use std::time::Duration;
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::u32;
 
-use hyper::client::{Client, Response};
+use auction_decoder::decode_auction_listings;
+use auction_stats::distinct_item_ids;
+use clock::{Clock, SystemClock};
+use flate2::read::GzDecoder;
+use hyper::Url;
+use hyper::client::Client;
+use hyper::header::{ContentLength, ContentType, ETag, EntityTag, Headers, HttpDate, IfModifiedSince, IfNoneMatch, LastModified};
+use hyper::mime::{Mime, SubLevel, TopLevel};
+use hyper::status::StatusCode;
+use item_cache::ItemCache;
+use metrics::{ClientMetrics, MetricsSnapshot};
 use regex::Regex;
-use rustc_serialize::{Decodable, json};
+use scoped_threadpool::Pool;
+#[cfg(feature = "rustc-serialize")]
+use rustc_serialize::{Decodable, Decoder, json};
+use serde::{Deserializer, Serialize, Serializer};
 use serde::de::Deserialize;
-use thread_throttler::ThreadThrottler;
+use thread_throttler::{ThreadThrottler, ThrottleMode};
+#[cfg(feature = "tracing")]
+use tracing;
+
+/// The ways a single attempt at a Blizzard API call can fail.
+#[derive(Debug)]
+pub enum BattleNetApiError {
+    /// The request couldn't be sent or the connection broke.
+    NetworkError(hyper::Error),
+    /// Blizzard replied with something other than 200 OK. Includes Blizzard's
+    /// explanation if the body had one.
+    HttpStatus(StatusCode, Option<BattleNetError>),
+    /// The body couldn't be read off the connection.
+    ReadError(io::Error),
+    /// Blizzard served an HTML page (usually an error page from their edge) where
+    /// we expected JSON.
+    UpstreamHtml,
+    /// Blizzard said the resource hasn't changed since we last fetched it.
+    NotModified,
+    /// Blizzard rejected our credentials, e.g. a wrong OAuth client secret.
+    AuthError(String),
+    /// The body decoded but didn't contain what it should have.
+    MalformedResponse(String),
+    /// The body didn't decode as what we asked for. The (sanitized) body is
+    /// kept so it can be inspected.
+    DecodeError(JsonError, String),
+    /// The call couldn't finish before its deadline.
+    TimedOut,
+    /// The client already sent as many requests as its `max_requests` allows.
+    QuotaExhausted,
+    /// The item endpoint has nothing for this id, usually because the item was
+    /// removed from the game.
+    ItemNotFound(u64),
+}
+
+impl fmt::Display for BattleNetApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BattleNetApiError::NetworkError(ref e) => write!(f, "{}", e),
+            BattleNetApiError::HttpStatus(ref status, None) => write!(f, "{}", status),
+            BattleNetApiError::HttpStatus(ref status, Some(ref error)) =>
+                write!(f, "{} ({}: {})", status, error.error_type, error.detail),
+            BattleNetApiError::ReadError(ref e) => write!(f, "failed to read body: {}", e),
+            BattleNetApiError::UpstreamHtml => write!(f, "Blizzard served an HTML page instead of JSON"),
+            BattleNetApiError::NotModified => write!(f, "not modified"),
+            BattleNetApiError::AuthError(ref reason) => write!(f, "authentication failed: {}", reason),
+            BattleNetApiError::MalformedResponse(ref problem) => write!(f, "malformed response: {}", problem),
+            BattleNetApiError::DecodeError(ref e, _) => write!(f, "failed to decode json: {}", e),
+            BattleNetApiError::TimedOut => write!(f, "deadline passed"),
+            BattleNetApiError::QuotaExhausted => write!(f, "request quota exhausted"),
+            BattleNetApiError::ItemNotFound(id) => write!(f, "no item with id {}", id),
+        }
+    }
+}
+
+/// The error a body that doesn't decode produces. Which JSON library decodes
+/// bodies depends on the `rustc-serialize` feature; see `decode_json`.
+#[cfg(feature = "rustc-serialize")]
+pub type JsonError = json::DecoderError;
+#[cfg(not(feature = "rustc-serialize"))]
+pub type JsonError = serde_json::Error;
+
+/// Types the client can decode from a JSON body: `Decodable` ones with the
+/// default `rustc-serialize` feature, serde `Deserialize` ones without it.
+#[cfg(feature = "rustc-serialize")]
+pub trait FromJson: Decodable {}
+#[cfg(feature = "rustc-serialize")]
+impl<T: Decodable> FromJson for T {}
+#[cfg(not(feature = "rustc-serialize"))]
+pub trait FromJson: Deserialize {}
+#[cfg(not(feature = "rustc-serialize"))]
+impl<T: Deserialize> FromJson for T {}
+
+/// Decodes a JSON body. Uses `rustc_serialize` when the `rustc-serialize` feature
+/// is enabled (the default), since it copes with everything Blizzard sends, and
+/// serde otherwise. Building with `--no-default-features` drops the dependency.
+#[cfg(feature = "rustc-serialize")]
+pub fn decode_json<T: FromJson>(body: &str) -> Result<T, JsonError> {
+    json::decode(body)
+}
+
+#[cfg(not(feature = "rustc-serialize"))]
+pub fn decode_json<T: FromJson>(body: &str) -> Result<T, JsonError> {
+    serde_json::from_str(body)
+}
+
+/// The body Blizzard sends along with error statuses, e.g.
+/// `{ "code": 404, "type": "BLZWEBAPI00000404", "detail": "Not Found" }`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct BattleNetError {
+    pub code: u64,
+    #[serde(rename = "type")]
+    pub error_type: String,
+    pub detail: String,
+}
+
+// Decoded by hand since `type` can't be a field name.
+#[cfg(feature = "rustc-serialize")]
+impl Decodable for BattleNetError {
+    fn decode<D: Decoder>(d: &mut D) -> Result<BattleNetError, D::Error> {
+        d.read_struct("BattleNetError", 3, |d| {
+            Ok(BattleNetError {
+                code: try!(d.read_struct_field("code", 0, Decodable::decode)),
+                error_type: try!(d.read_struct_field("type", 1, Decodable::decode)),
+                detail: try!(d.read_struct_field("detail", 2, Decodable::decode)),
+            })
+        })
+    }
+}
+
+/// The regions Blizzard runs separate APIs for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Region {
+    Us,
+    Eu,
+    Kr,
+    Tw,
+    Cn,
+}
+
+impl Region {
+    /// The host serving this region's community API.
+    pub fn api_host(&self) -> &'static str {
+        match *self {
+            Region::Us => "us.api.battle.net",
+            Region::Eu => "eu.api.battle.net",
+            Region::Kr => "kr.api.battle.net",
+            Region::Tw => "tw.api.battle.net",
+            Region::Cn => "api.battlenet.com.cn",
+        }
+    }
+
+    /// The host serving this region's game data API (the `/data/wow/` endpoints).
+    pub fn data_host(&self) -> &'static str {
+        match *self {
+            Region::Us => "us.api.blizzard.com",
+            Region::Eu => "eu.api.blizzard.com",
+            Region::Kr => "kr.api.blizzard.com",
+            Region::Tw => "tw.api.blizzard.com",
+            Region::Cn => "gateway.battlenet.com.cn",
+        }
+    }
+
+    /// The region's suffix in game data namespaces, e.g. `dynamic-us`.
+    pub fn namespace_suffix(&self) -> &'static str {
+        match *self {
+            Region::Us => "us",
+            Region::Eu => "eu",
+            Region::Kr => "kr",
+            Region::Tw => "tw",
+            Region::Cn => "cn",
+        }
+    }
+
+    /// The host serving this region's renders, such as item icons.
+    pub fn render_host(&self) -> &'static str {
+        match *self {
+            Region::Us => "render-us.worldofwarcraft.com",
+            Region::Eu => "render-eu.worldofwarcraft.com",
+            Region::Kr => "render-kr.worldofwarcraft.com",
+            Region::Tw => "render-tw.worldofwarcraft.com",
+            Region::Cn => "render.worldofwarcraft.com.cn",
+        }
+    }
+}
+
+/// The sizes Blizzard renders item icons at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IconSize {
+    Small,
+    Medium,
+    Large,
+}
+
+impl IconSize {
+    /// Width and height of the icon in pixels.
+    pub fn pixels(&self) -> u32 {
+        match *self {
+            IconSize::Small => 18,
+            IconSize::Medium => 36,
+            IconSize::Large => 56,
+        }
+    }
+}
 
 /// The content we care about in the realm status response.
-#[derive(Debug, Serialize, Deserialize, RustcDecodable)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "rustc-serialize", derive(RustcDecodable))]
 pub struct RealmInfo {
     pub name: String,
     pub slug: String,
     pub connected_realms: Vec<String>,
+    /// None if Blizzard didn't say.
+    #[serde(default)]
+    pub population: Option<RealmPopulation>,
+}
+
+/// Whether a realm is up. Blizzard's status strings we don't know yet come back
+/// as `Unknown` rather than failing to decode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RealmStatus {
+    Up,
+    Down,
+    /// Any other status, as Blizzard sent it.
+    Unknown(String),
+}
+
+impl RealmStatus {
+    /// Parses a status type, e.g. `"UP"`. Case doesn't matter.
+    pub fn from_type(kind: &str) -> RealmStatus {
+        match &kind.to_uppercase()[..] {
+            "UP" => RealmStatus::Up,
+            "DOWN" => RealmStatus::Down,
+            _ => RealmStatus::Unknown(kind.to_owned()),
+        }
+    }
+}
+
+/// How busy a realm is. Blizzard adds tiers now and then, so ones we don't know
+/// yet come back as `Unknown` rather than failing to decode the realm list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RealmPopulation {
+    Low,
+    Medium,
+    High,
+    Full,
+    Locked,
+    /// Any other population, as Blizzard sent it.
+    Unknown(String),
+}
+
+impl RealmPopulation {
+    /// Parses a population, e.g. `"medium"` (community API) or `"MEDIUM"` (game
+    /// data API).
+    pub fn from_type(kind: &str) -> RealmPopulation {
+        match &kind.to_uppercase()[..] {
+            "LOW" => RealmPopulation::Low,
+            "MEDIUM" => RealmPopulation::Medium,
+            "HIGH" => RealmPopulation::High,
+            "FULL" => RealmPopulation::Full,
+            "LOCKED" => RealmPopulation::Locked,
+            _ => RealmPopulation::Unknown(kind.to_owned()),
+        }
+    }
+
+    /// The population as the community API writes it, e.g. `"medium"`.
+    pub fn as_str(&self) -> &str {
+        match *self {
+            RealmPopulation::Low => "low",
+            RealmPopulation::Medium => "medium",
+            RealmPopulation::High => "high",
+            RealmPopulation::Full => "full",
+            RealmPopulation::Locked => "locked",
+            RealmPopulation::Unknown(ref kind) => kind,
+        }
+    }
+}
+
+#[cfg(feature = "rustc-serialize")]
+impl Decodable for RealmPopulation {
+    fn decode<D: Decoder>(d: &mut D) -> Result<RealmPopulation, D::Error> {
+        d.read_str().map(|kind| RealmPopulation::from_type(&kind))
+    }
+}
+
+impl Deserialize for RealmPopulation {
+    fn deserialize<D: Deserializer>(deserializer: &mut D) -> Result<RealmPopulation, D::Error> {
+        String::deserialize(deserializer).map(|kind| RealmPopulation::from_type(&kind))
+    }
+}
+
+impl Serialize for RealmPopulation {
+    fn serialize<S: Serializer>(&self, serializer: &mut S) -> Result<(), S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
 }
 
 /// Content we care about in an item info response.
-#[derive(Debug, Deserialize, RustcDecodable)]
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(feature = "rustc-serialize", derive(RustcDecodable))]
 pub struct ItemInfo {
     pub id: u64,
     pub name: String,
     pub icon: String,
+    /// What a vendor sells the item for in copper, for items vendors sell. Blizzard
+    /// calls it `purchasePrice`.
+    pub purchase_price: Option<u64>,
+    /// The item's class and subclass ids (`itemClass` and `itemSubClass`), when
+    /// included. See `item_cache::ItemClassifier`.
+    pub item_class: Option<u64>,
+    pub item_subclass: Option<u64>,
+}
+
+impl ItemInfo {
+    /// Stands in for an item whose info can't be had, named `"item:<id>"`.
+    pub fn placeholder(id: u64) -> ItemInfo {
+        ItemInfo {
+            id: id,
+            name: format!("item:{}", id),
+            icon: String::new(),
+            purchase_price: None,
+            item_class: None,
+            item_subclass: None,
+        }
+    }
+
+    /// Builds the URL of this item's icon on `render_host`. Usually you want
+    /// `BattleNetApiClient::icon_url`, which picks the host for you.
+    pub fn icon_url(&self, render_host: &str, size: IconSize) -> String {
+        format!("https://{}/icons/{}/{}.jpg", render_host, size.pixels(), self.icon)
+    }
+}
+
+/// Picks the name for `locale` out of a per-locale name object, falling back to
+/// `en_US` and then to whichever one there is.
+fn pick_locale<'a, V>(names: &'a BTreeMap<String, V>, locale: &str) -> Option<&'a V> {
+    names.get(locale).or_else(|| names.get("en_US")).or_else(|| names.values().next())
+}
+
+/// Item info fields Blizzard names in camel case, with the names `ItemInfo` uses.
+const RENAMED_ITEM_FIELDS: &'static [(&'static str, &'static str)] = &[
+    ("purchasePrice", "purchase_price"),
+    ("itemClass", "item_class"),
+    ("itemSubClass", "item_subclass"),
+];
+
+/// Decodes an item info response. Depending on the endpoint `name` is either a
+/// string or an object with a name per locale, e.g. `{"en_US": "...", "de_DE": "..."}`,
+/// in which case the one for `locale` is kept (see `pick_locale`).
+#[cfg(feature = "rustc-serialize")]
+fn decode_item_info(body: &str, locale: &str) -> Result<ItemInfo, JsonError> {
+    let mut item = json::Json::from_str(body).map_err(json::DecoderError::ParseError)?;
+    if let json::Json::Object(ref mut fields) = item {
+        let picked = match fields.get("name") {
+            Some(&json::Json::Object(ref names)) => pick_locale(names, locale).cloned(),
+            _ => None,
+        };
+        if let Some(name) = picked {
+            fields.insert("name".to_owned(), name);
+        }
+        for &(blizzard, ours) in RENAMED_ITEM_FIELDS {
+            if let Some(value) = fields.remove(blizzard) {
+                fields.insert(ours.to_owned(), value);
+            }
+        }
+    }
+    ItemInfo::decode(&mut json::Decoder::new(item))
+}
+
+#[cfg(not(feature = "rustc-serialize"))]
+fn decode_item_info(body: &str, locale: &str) -> Result<ItemInfo, JsonError> {
+    let mut item: serde_json::Value = serde_json::from_str(body)?;
+    if let serde_json::Value::Object(ref mut fields) = item {
+        let picked = match fields.get("name") {
+            Some(&serde_json::Value::Object(ref names)) => pick_locale(names, locale).cloned(),
+            _ => None,
+        };
+        if let Some(name) = picked {
+            fields.insert("name".to_owned(), name);
+        }
+        for &(blizzard, ours) in RENAMED_ITEM_FIELDS {
+            if let Some(value) = fields.remove(blizzard) {
+                fields.insert(ours.to_owned(), value);
+            }
+        }
+    }
+    serde_json::value::from_value(item)
+}
+
+/// The WoW Token's price at some point in time.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[cfg_attr(feature = "rustc-serialize", derive(RustcDecodable))]
+pub struct TokenPrice {
+    /// When Blizzard last updated the price, in milliseconds since the epoch.
+    pub last_updated_timestamp: u64,
+    /// The price in copper.
+    pub price: u64,
+}
+
+/// A `{ "type": ..., "name": ... }` pair as used by the game data API for
+/// things like realm status and population. We only keep the type since the
+/// name is just its localized display string.
+#[derive(Debug, Deserialize)]
+struct TypedName {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+#[cfg(feature = "rustc-serialize")]
+impl Decodable for TypedName {
+    fn decode<D: Decoder>(d: &mut D) -> Result<TypedName, D::Error> {
+        d.read_struct("TypedName", 2, |d| {
+            Ok(TypedName {
+                kind: try!(d.read_struct_field("type", 0, Decodable::decode)),
+            })
+        })
+    }
+}
+
+/// A realm as the game data API describes it.
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "rustc-serialize", derive(RustcDecodable))]
+struct DataRealm {
+    name: String,
+    slug: String,
+}
+
+/// The reply from the game data API's connected realm endpoint.
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "rustc-serialize", derive(RustcDecodable))]
+struct ConnectedRealmReply {
+    id: u64,
+    has_queue: bool,
+    status: TypedName,
+    population: TypedName,
+    realms: Vec<DataRealm>,
+}
+
+/// An `{ "id": ..., "name": ... }` reference to something in the game data API,
+/// e.g. an entry in the profession index.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "rustc-serialize", derive(RustcDecodable))]
+pub struct NamedRef {
+    pub id: u64,
+    pub name: String,
+}
+
+/// A profession and its skill tiers (one per expansion, e.g. "Legion Alchemy").
+#[derive(Debug, Clone)]
+pub struct Profession {
+    pub id: u64,
+    pub name: String,
+    pub skill_tiers: Vec<NamedRef>,
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "rustc-serialize", derive(RustcDecodable))]
+struct ProfessionIndexReply {
+    professions: Vec<NamedRef>,
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "rustc-serialize", derive(RustcDecodable))]
+struct ProfessionReply {
+    id: u64,
+    name: String,
+    /// Missing for professions without recipes, like Archaeology.
+    skill_tiers: Option<Vec<NamedRef>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "rustc-serialize", derive(RustcDecodable))]
+struct SkillTierReply {
+    categories: Option<Vec<RecipeCategory>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "rustc-serialize", derive(RustcDecodable))]
+struct RecipeCategory {
+    recipes: Vec<NamedRef>,
+}
+
+/// One page of a game data API search.
+#[derive(Debug, Clone)]
+pub struct SearchPage<T> {
+    /// This page's number, starting from 1.
+    pub page: u32,
+    pub page_count: u32,
+    /// Whether Blizzard stopped counting matches, in which case later pages don't
+    /// hold everything that matched and the search should be narrowed.
+    pub results_capped: bool,
+    pub results: Vec<T>,
 }
 
-/// Represents the reply from blizzard's auction data urls.
-#[derive(Debug, Deserialize, RustcDecodable)]
-struct AuctionListingsReply {
-    realms: Vec<BTreeMap<String, String>>,  // Can't re-use RealmInfo because no connected_realms.
-    auctions: Vec<AuctionListing>,
+/// An item found by `search_items`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ItemSearchResult {
+    pub id: u64,
+    /// The item's en_US name.
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "rustc-serialize", derive(RustcDecodable))]
+#[allow(non_snake_case)]
+struct SearchReply<T> {
+    page: u32,
+    pageCount: u32,
+    resultCountCapped: Option<bool>,
+    results: Vec<SearchResult<T>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "rustc-serialize", derive(RustcDecodable))]
+struct SearchResult<T> {
+    data: T,
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "rustc-serialize", derive(RustcDecodable))]
+#[allow(non_snake_case)]
+struct EnglishName {
+    en_US: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "rustc-serialize", derive(RustcDecodable))]
+struct ItemSearchData {
+    id: u64,
+    name: EnglishName,
+}
+
+/// A group of connected realms, which share an auction house.
+#[derive(Debug, Clone)]
+pub struct ConnectedRealm {
+    pub id: u64,
+    /// The member realms. Each one's `connected_realms` lists every member's slug.
+    pub realms: Vec<RealmInfo>,
+    pub status: RealmStatus,
+    pub population: RealmPopulation,
+    pub has_queue: bool,
 }
 
 /// Represents the JSON reply from the auction data status endpoint.
-#[derive(Debug, Deserialize, RustcDecodable)]
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "rustc-serialize", derive(RustcDecodable))]
 #[allow(non_snake_case)]
-struct AuctionDataPointer {
+struct AuctionDataFileReply {
     url: String,
     lastModified: u64,
 }
 
-#[derive(Debug, Deserialize, RustcDecodable)]
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "rustc-serialize", derive(RustcDecodable))]
 struct AuctionDataReply {
-    files: Vec<AuctionDataPointer>, // Will always be 1 element.
+    files: Vec<AuctionDataFileReply>, // Usually 1 element, but Blizzard sometimes shards dumps.
+}
+
+/// Where a realm's current auction dump lives, as returned by
+/// `resolve_auction_pointer`. Pass it to `download_auction_data` to fetch the dump.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuctionDataPointer {
+    pub realm_slug: String,
+    /// When the newest of the dump's files was last modified.
+    pub last_modified: u64,
+    /// Every file making up the dump as (last modified, URL) pairs.
+    pub files: Vec<(u64, String)>,
 }
 
 /// The fields we care about in blizzard's auction reply.
-#[derive(Debug, Deserialize, RustcDecodable)]
+#[derive(Debug, Default, Clone, Deserialize)]
+#[cfg_attr(feature = "rustc-serialize", derive(RustcDecodable))]
+#[allow(non_snake_case)]
 pub struct AuctionListing {
     pub item: u64,
+    /// Whole-stack buyout. Zero when the listing has no buyout.
+    #[serde(default)]
     pub buyout: u64,
     pub quantity: u64,
+    /// Per-unit price, only present on commodity listings.
+    pub unit_price: Option<u64>,
+    /// Whole-stack bid, when Blizzard includes it.
+    pub bid: Option<u64>,
+    /// Blizzard's coarse time remaining, e.g. `"LONG"`, when included.
+    pub timeLeft: Option<String>,
+    /// Bonuses applied to the item (item level, sockets, etc.), when included.
+    pub bonusLists: Option<Vec<BonusList>>,
+    /// Item modifiers, e.g. the level a scaling item was looted at, when included.
+    pub modifiers: Option<Vec<ItemModifier>>,
 }
 
-pub struct BattleNetApiClient {
-    pub token: String,
+/// One entry in a listing's `bonusLists`, e.g. `{ "bonusListId": 1512 }`.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[cfg_attr(feature = "rustc-serialize", derive(RustcDecodable))]
+#[allow(non_snake_case)]
+pub struct BonusList {
+    pub bonusListId: u64,
+}
+
+/// One entry in a listing's `modifiers`, e.g. `{ "type": 9, "value": 110 }`.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct ItemModifier {
+    #[serde(rename = "type")]
+    pub modifier_type: u64,
+    pub value: u64,
+}
+
+#[cfg(feature = "rustc-serialize")]
+impl Decodable for ItemModifier {
+    fn decode<D: Decoder>(d: &mut D) -> Result<ItemModifier, D::Error> {
+        d.read_struct("ItemModifier", 2, |d| {
+            Ok(ItemModifier {
+                modifier_type: try!(d.read_struct_field("type", 0, Decodable::decode)),
+                value: try!(d.read_struct_field("value", 1, Decodable::decode)),
+            })
+        })
+    }
+}
+
+impl AuctionListing {
+    /// The per-unit price of this listing in copper, whichever way Blizzard
+    /// priced it. Price helpers should use this rather than dividing `buyout`
+    /// themselves, otherwise commodity prices get divided twice.
+    pub fn unit_price(&self) -> u64 {
+        match self.unit_price {
+            Some(unit_price) => unit_price,
+            None if self.quantity == 0 => 0,
+            None => self.buyout / self.quantity,
+        }
+    }
+
+    /// The price of buying out the whole listing in copper.
+    pub fn total_buyout(&self) -> u64 {
+        match self.unit_price {
+            Some(unit_price) => unit_price * self.quantity,
+            None => self.buyout,
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+fn duration_ms(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1_000_000
+}
+
+/// Whether an auction dump last modified at `last_modified` (milliseconds since the
+/// epoch, as Blizzard reports it) was more than `max_age` old at `now`. A realm whose
+/// dump stops updating is usually having problems, and its prices can't be trusted.
+pub fn is_stale_at(last_modified: u64, max_age: Duration, now: SystemTime) -> bool {
+    match now.duration_since(UNIX_EPOCH + Duration::from_millis(last_modified)) {
+        Ok(age) => age > max_age,
+        // Modified in the future as far as we can tell, so certainly not stale.
+        Err(_) => false,
+    }
+}
+
+/// Like `is_stale_at`, as of right now.
+pub fn is_stale(last_modified: u64, max_age: Duration) -> bool {
+    is_stale_at(last_modified, max_age, SystemTime::now())
+}
+
+/// The identifier embedded in a legacy auction data-file URL, e.g.
+/// `http://auction-api-us.worldofwarcraft.com/auction-data/<id>/auctions.json`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuctionDataFileId {
+    /// A numeric connected-realm id.
+    ConnectedRealm(u64),
+    /// An opaque 32 character hex hash. These are shared by every realm in a
+    /// connected realm, so they can be used to correlate dumps.
+    Hash(String),
+    /// A realm slug, as used by older dumps.
+    Slug(String),
+}
+
+/// Extracts the realm identifier from an auction data-file URL, or None if the URL
+/// doesn't look like one we know about. Blizzard changes these, so expect None.
+pub fn parse_auction_data_url(url: &str) -> Option<AuctionDataFileId> {
+    let re = Regex::new("/auction-data/([^/]+)/auctions\\.json$").unwrap();
+    let id = match re.captures(url).and_then(|caps| caps.at(1)) {
+        Some(id) => id,
+        None => return None,
+    };
+    if let Ok(connected_realm_id) = id.parse::<u64>() {
+        Some(AuctionDataFileId::ConnectedRealm(connected_realm_id))
+    } else if id.len() == 32 && id.chars().all(|c| c.is_digit(16)) {
+        Some(AuctionDataFileId::Hash(id.to_lowercase()))
+    } else if id.chars().all(|c| c.is_lowercase() || c.is_digit(10) || c == '-') {
+        Some(AuctionDataFileId::Slug(id.to_owned()))
+    } else {
+        None
+    }
+}
+
+/// Preprocesses a response body before it's decoded, e.g. to scrub fields that
+/// Blizzard fills with garbage.
+pub type BodyFilter = for<'a> Fn(&'a str) -> Cow<'a, str>;
+
+/// Replaces the contents of every `owner` field with an underscore. Blizzard often
+/// puts invalid unicode in auction owner names which breaks decoding, and we don't
+/// use them anyway.
+pub fn strip_owners(body: &str) -> Cow<str> {
+    OWNER_FIELD.with(|re| Cow::Owned(re.replace_all(body, "\"owner\":\"_\"")))
+}
+
+// Compiled once per thread rather than on every body `strip_owners` sees.
+thread_local!(static OWNER_FIELD: Regex = Regex::new("\"owner\":\"([^\"]+?)\"").unwrap());
+
+/// A response as the client sees it, with the body already read.
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+/// Sends the client's GET requests. The default, `HyperTransport`, goes over the
+/// network; supplying another one lets tests (or a replay tool) serve canned
+/// responses without touching the network.
+pub trait Transport: Send + Sync {
+    fn get(&self, url: &str, headers: Headers) -> Result<HttpResponse, BattleNetApiError>;
+
+    /// Sends a HEAD request. The default falls back to a GET, which gets the same
+    /// headers at the cost of downloading the body.
+    fn head(&self, url: &str, headers: Headers) -> Result<HttpResponse, BattleNetApiError> {
+        self.get(url, headers)
+    }
+
+    /// Sends a GET request and copies the body into `writer` instead of returning it,
+    /// returning the status and how many bytes were written. The body is only written
+    /// if the status is 200. The default buffers the whole body with `get`; transports
+    /// that can should copy it over as it arrives.
+    fn get_to(&self, url: &str, headers: Headers, writer: &mut Write) -> Result<(StatusCode, u64), BattleNetApiError> {
+        let res = self.get(url, headers)?;
+        if res.status != StatusCode::Ok {
+            return Ok((res.status, 0));
+        }
+        writer.write_all(&res.body).map_err(BattleNetApiError::ReadError)?;
+        Ok((res.status, res.body.len() as u64))
+    }
+}
+
+/// The default Transport: a hyper client.
+///
+/// This only speaks HTTP/1.1, as hyper 0.10 has no HTTP/2 support, so there's no
+/// option to negotiate HTTP/2 here. Connections are kept alive and pooled per host,
+/// so back-to-back status and item calls reuse a connection rather than opening a
+/// new one each time. To multiplex over HTTP/2 anyway, implement `Transport` on top
+/// of a client that supports it and pass it to `BattleNetApiClientBuilder::transport`.
+pub struct HyperTransport {
     client: Client,
-    tt: ThreadThrottler,
 }
 
-impl BattleNetApiClient {
-    pub fn new(token: &str) -> BattleNetApiClient {
-        BattleNetApiClient {
-            token: token.to_owned(),
-            client: Client::new(),
-            tt: ThreadThrottler::new(100, Duration::new(1, 0)),
+impl HyperTransport {
+    pub fn new() -> HyperTransport {
+        HyperTransport::with_client(Client::new())
+    }
+
+    /// Sends requests with an already configured hyper client, e.g. one with a custom
+    /// connector. See also `tls::tls_transport`.
+    pub fn with_client(client: Client) -> HyperTransport {
+        HyperTransport { client: client }
+    }
+}
+
+impl Transport for HyperTransport {
+    fn get(&self, url: &str, headers: Headers) -> Result<HttpResponse, BattleNetApiError> {
+        let mut res = match self.client.get(url).headers(headers).send() {
+            Ok(r) => r,
+            Err(e) => return Err(BattleNetApiError::NetworkError(e)),
+        };
+        let mut body = Vec::new();
+        if let Err(e) = res.read_to_end(&mut body) {
+            return Err(BattleNetApiError::ReadError(e));
         }
+        Ok(HttpResponse {
+            status: res.status,
+            headers: res.headers.clone(),
+            body: body,
+        })
     }
 
-    /// Try to retrieve something from the Blizzard API. Will retry indefinitely.
-    /// Returns the body as a String.
-    /// `task` will be used for error messages.
-    /// TODO: Really this should try to decode the json as well and be type
-    /// inferred from context.
-    fn make_blizzard_api_call<T: Decodable>(&self, url: &str, task: &str) -> T {
-        let mut s = String::new();
-        let mut retries = 0;
+    fn get_to(&self, url: &str, headers: Headers, writer: &mut Write) -> Result<(StatusCode, u64), BattleNetApiError> {
+        let mut res = match self.client.get(url).headers(headers).send() {
+            Ok(r) => r,
+            Err(e) => return Err(BattleNetApiError::NetworkError(e)),
+        };
+        if res.status != StatusCode::Ok {
+            return Ok((res.status, 0));
+        }
+        // io::copy goes through a small fixed buffer, so memory use doesn't grow
+        // with the size of the dump.
+        let copied = io::copy(&mut res, writer).map_err(BattleNetApiError::ReadError)?;
+        Ok((res.status, copied))
+    }
 
-        loop {
-            let mut res: Response;
-            retries += 1;
+    fn head(&self, url: &str, headers: Headers) -> Result<HttpResponse, BattleNetApiError> {
+        match self.client.head(url).headers(headers).send() {
+            Ok(res) => Ok(HttpResponse {
+                status: res.status,
+                headers: res.headers.clone(),
+                body: Vec::new(),
+            }),
+            Err(e) => Err(BattleNetApiError::NetworkError(e)),
+        }
+    }
+}
 
-            self.tt.pass_through_or_block();
-            match self.client.get(url).send() {
-                Ok(r) => res = r,
-                Err(e) => {
-                    println!("Error downloading {}: {}. Retry {}.", task, e, retries);
-                    continue;
-                },
-            }
-            // TODO: 404 should really be handled differently here. Maybe make this return a Result<T>?
-            // That would let us account for unrecoverable errors.
-            if res.status != hyper::Ok {
-                println!("Error downloading {}: {}. Retry {}.", task, res.status, retries);
-                continue;
-            }
-            match res.read_to_string(&mut s) {
-                Ok(_) => (),
-                Err(e) => {
-                    println!("Failed to process {}: {}. Retry {}.", task, e, retries);
-                    continue;
-                },
-            }
-            // TODO: Fix this file to use serde instead.
-            //Sometimes the auction listings contain invalid unicode. Strip that:
-            // s = String::from_utf8_lossy(s.as_bytes()).into_owned();
-            // But even then, we're getting json errors. Until we solve that, use
-            // rustc_serialize.
-            // TODO: This is a hack replacing the contents of the 'owner' field with an
-            // underscore. This is because blizzard often times put garbage in there. Improve this
-            // situation slightly by removing the regex from the loop (we shouldn't be compiling
-            // it in here anyway, and restructing the function to return a string. The string can
-            // be processed in individual api calls.
-            let re = Regex::new("\"owner\":\"([^\"]+?)\"").unwrap();
-            let s = re.replace_all(&s, "\"owner\":\"_\"");
-            match json::decode(&s) {
-                Ok(obj) => return obj,
-                Err(e) => {
-                    println!("Failed to decode json for {}: {}. Retry {}.", task, e, retries);
-                },
-            }
+/// Turns a raw response body into the JSON text the rest of the request pipeline
+/// (body filters and typed decoding) works with. The client picks one by the
+/// response's Content-Type, falling back to `JsonContentDecoder`, so a more
+/// compact wire format can be supported by registering a ContentDecoder that
+/// translates it, without touching the request code.
+pub trait ContentDecoder: Send + Sync {
+    /// Whether this decoder handles bodies served as `content_type`.
+    fn handles(&self, content_type: &Mime) -> bool;
+    /// Converts a body into JSON text.
+    fn decode_content(&self, body: Vec<u8>) -> Result<String, BattleNetApiError>;
+}
+
+/// The default ContentDecoder: bodies are already UTF-8 JSON.
+pub struct JsonContentDecoder;
+
+impl ContentDecoder for JsonContentDecoder {
+    fn handles(&self, content_type: &Mime) -> bool {
+        match *content_type {
+            Mime(TopLevel::Application, SubLevel::Json, _) => true,
+            _ => false,
         }
     }
 
-    /// Downloads a list of realms from the Blizzard API.
-    pub fn get_realms(&self) -> Vec<RealmInfo> {
-        let mut realm_data: BTreeMap<String, Vec<RealmInfo>> =
-            self.make_blizzard_api_call(&format!("https://us.api.battle.net/wow/realm/status?locale=en_US&apikey={}", self.token), "realm status");
-        realm_data.remove("realms").expect("Malformed realm response.")
+    fn decode_content(&self, body: Vec<u8>) -> Result<String, BattleNetApiError> {
+        String::from_utf8(body).map_err(|e| BattleNetApiError::ReadError(io::Error::new(io::ErrorKind::InvalidData, e)))
     }
+}
 
-    /// Downloads the auction listings for the specified realm, or None if the listings haven't
-    /// been updated since `cutoff`.
-    pub fn get_auction_listings(&self, realm_slug: &str, cutoff: u64) -> Option<(u64, Vec<AuctionListing>)> {
-        let mut auction_data_reply: AuctionDataReply =
-            self.make_blizzard_api_call(
-                &format!("https://us.api.battle.net/wow/auction/data/{}?locale=en_US&apikey={}", realm_slug, self.token),
-                &format!("auction data for {}", realm_slug)
-            );
-        let auction_data_pointer = auction_data_reply.files.pop().unwrap();
-        if auction_data_pointer.lastModified <= cutoff {
-            return None
-        }
-        let auction_listings_data: AuctionListingsReply =
-            self.make_blizzard_api_call(&auction_data_pointer.url, &format!("auction listings for {}", realm_slug));
-        Some((auction_data_pointer.lastModified, auction_listings_data.auctions))
+/// Called with the URL and headers of every request just before it's sent: API
+/// calls, auction dump downloads and HEAD probes, and icon downloads. It can add
+/// headers (e.g. for signing) or just observe the request for logging or metrics.
+/// It runs once per attempt, so retries are seen too.
+pub type RequestHook = Fn(&str, &mut Headers) + Send + Sync;
+
+/// Decodes a (filtered) response body. Most calls use `decode_json`, but large
+/// responses can use a specialized decoder.
+type BodyDecoder<T> = for<'a> Fn(&'a str) -> Result<T, JsonError>;
+
+/// The part of handling a response shared by network calls and `parse_auction_file`:
+/// rejects HTML, applies `filter` if given and decodes what's left.
+fn decode_body<T>(s: &str, filter: Option<&BodyFilter>, decode: &BodyDecoder<T>) -> Result<T, BattleNetApiError> {
+    // Blizzard's edge sometimes serves an HTML error page with a 200. That's their
+    // problem rather than a decoding problem, so report it separately.
+    if s.trim_left().starts_with('<') {
+        return Err(BattleNetApiError::UpstreamHtml);
     }
+    // TODO: Make serde the default (i.e. drop the `rustc-serialize` feature) once
+    // it's been validated against real dumps.
+    //Sometimes the auction listings contain invalid unicode. Strip that:
+    // s = String::from_utf8_lossy(s.as_bytes()).into_owned();
+    // But even then, we're getting json errors. Until we solve that, use
+    // rustc_serialize.
+    let body = match filter {
+        Some(filter) => filter(s),
+        None => Cow::Borrowed(s),
+    };
+    decode(&body).map_err(|e| BattleNetApiError::DecodeError(e, body.into_owned()))
+}
 
-    /// Helpler function to process a vec of RealmInfo's into vec's of slugs for
-    /// connected realms. Connected realms share an auction house.
-    pub fn process_connected_realms(realm_infos: &Vec<RealmInfo>) -> Vec<Vec<String>> {
-        let mut realm_sets: Vec<Vec<String>> = realm_infos.into_iter().map(|r|
-            r.connected_realms.clone()
-        ).collect();
+/// Reads an auction data file saved to disk (e.g. by `ArchiveConfig::write_dump`)
+/// and decodes it exactly like a downloaded one, owner stripping included. Files
+/// ending in `.gz` are gunzipped first. The last modified time comes from the file
+/// name if it was archived with `NamingScheme::SlugTimestamp`, otherwise from the
+/// file's modification time, in milliseconds either way.
+pub fn parse_auction_file(path: &Path) -> Result<(u64, Vec<AuctionListing>), BattleNetApiError> {
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_else(String::new);
+    let gzipped = name.ends_with(".gz");
+    let mut body = Vec::new();
+    let read = File::open(path).and_then(|file| {
+        if gzipped {
+            GzDecoder::new(file).and_then(|mut decoder| decoder.read_to_end(&mut body))
+        } else {
+            let mut file = file;
+            file.read_to_end(&mut body)
+        }
+    });
+    if let Err(e) = read {
+        return Err(BattleNetApiError::ReadError(e));
+    }
+    let stem = name.trim_right_matches(".gz").trim_right_matches(".json");
+    let last_modified = match stem.rsplit('_').next().and_then(|timestamp| timestamp.parse::<u64>().ok()) {
+        Some(last_modified) if stem.contains('_') => last_modified,
+        _ => fs::metadata(path).and_then(|metadata| metadata.modified())
+            .map(|modified| modified.duration_since(UNIX_EPOCH).map(|age| age.as_secs() * 1000).unwrap_or(0))
+            .map_err(BattleNetApiError::ReadError)?,
+    };
+    let s = JsonContentDecoder.decode_content(body)?;
+    let listings = decode_body(&s, Some(&strip_owners as &BodyFilter), &decode_auction_listings)?;
+    Ok((last_modified, listings))
+}
 
-        // This dedup logic relies on the ordering within a connected realms list being the same
-        // for all realms in the list.
-        realm_sets.sort_by(|a, b| a.iter().next().unwrap().cmp(b.iter().next().unwrap()));
-        realm_sets.dedup();
-        return realm_sets;
+/// Cache validators from the last successful fetch of a URL, sent back to make
+/// the next fetch conditional.
+#[derive(Debug, Clone)]
+struct Validators {
+    etag: Option<EntityTag>,
+    last_modified: Option<HttpDate>,
+}
+
+/// Pulls the realm list out of a decoded realm status response. An empty list is
+/// fine (Blizzard sends one during maintenance) but a missing one isn't.
+fn realms_from_status(mut realm_data: BTreeMap<String, Vec<RealmInfo>>) -> Result<Vec<RealmInfo>, BattleNetApiError> {
+    match realm_data.remove("realms") {
+        Some(realms) => Ok(realms),
+        None => Err(BattleNetApiError::MalformedResponse("realm status has no realms".to_owned())),
     }
+}
+
+/// Default time to keep serving the realm list before fetching it again.
+const DEFAULT_REALM_CACHE_TTL_SECS: u64 = 60 * 60;
+
+/// Default number of repeats of an identical error between summary log lines.
+const DEFAULT_FAILURE_SUMMARY_EVERY: u32 = 50;
+
+/// Number of threads `resolve_names` fetches item info with.
+const RESOLVE_NAMES_THREADS: u32 = 5;
+
+/// The item info fields the crate reads, requested when field filtering is on.
+const ITEM_FIELDS: &'static str = "id,name,icon,purchasePrice,itemClass,itemSubClass";
+
+/// The realm status fields the crate reads, requested when field filtering is on.
+const REALM_FIELDS: &'static str = "realms.name,realms.slug,realms.connected_realms,realms.population";
+
+/// Controls how a call retries and backs off between retries.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryConfig {
+    /// How many times a failed call is retried before its error is returned.
+    /// 0 makes every call single-shot. Defaults to `u32::MAX`, which in practice
+    /// means forever.
+    pub max_retries: u32,
+    /// How long to wait before the first retry.
+    pub initial_backoff: Duration,
+    /// The wait doubles after every failed retry up to this limit.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> RetryConfig {
+        RetryConfig {
+            max_retries: u32::MAX,
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+/// What a `RetryPolicy` wants done about a failed attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryDecision {
+    /// Back off and try again.
+    Retry,
+    /// Return the error.
+    GiveUp,
+}
+
+/// Decides which failed attempts are worth retrying. The client consults it after
+/// every failed attempt, with the HTTP status if Blizzard sent one and how many
+/// attempts have been made so far, starting at 1. Backoff between retries still
+/// follows the client's `RetryConfig`.
+///
+/// `NotModified`, `TimedOut` and `QuotaExhausted` aren't failures in this sense
+/// and are always returned straight away without asking the policy.
+pub trait RetryPolicy: Send + Sync {
+    fn should_retry(&self, status: Option<StatusCode>, attempt: u32, error: &BattleNetApiError) -> RetryDecision;
+}
 
-    pub fn get_item_info(&self, id: u64) -> ItemInfo {
-        self.make_blizzard_api_call::<ItemInfo>(&format!("https://us.api.battle.net/wow/item/{}?locale=en_US&apikey={}", id, self.token), "item info")
+/// The policy clients use unless given another: retries any failure up to `max_retries`
+/// times, except that in fail-fast mode a response that doesn't decode is given up on
+/// at once. Built from the builder's `retry_config` and `fail_fast` settings.
+#[derive(Debug, Clone, Copy)]
+pub struct DefaultRetryPolicy {
+    pub max_retries: u32,
+    pub fail_fast: bool,
+}
+
+impl RetryPolicy for DefaultRetryPolicy {
+    fn should_retry(&self, _: Option<StatusCode>, attempt: u32, error: &BattleNetApiError) -> RetryDecision {
+        match *error {
+            BattleNetApiError::DecodeError(..) if self.fail_fast => RetryDecision::GiveUp,
+            _ if attempt > self.max_retries => RetryDecision::GiveUp,
+            _ => RetryDecision::Retry,
+        }
+    }
+}
+
+/// Wraps the client's policy for item requests: a 404 there means the item was
+/// removed, which asking again won't fix, so it gives up straight away.
+struct ItemRetryPolicy<'a>(&'a RetryPolicy);
+
+impl<'a> RetryPolicy for ItemRetryPolicy<'a> {
+    fn should_retry(&self, status: Option<StatusCode>, attempt: u32, error: &BattleNetApiError) -> RetryDecision {
+        if status == Some(StatusCode::NotFound) {
+            return RetryDecision::GiveUp;
+        }
+        self.0.should_retry(status, attempt, error)
+    }
+}
+
+/// How `get_auction_listings` compares a dump's `lastModified` with the cutoff it's
+/// given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CutoffMode {
+    /// Fetch only dumps strictly newer than the cutoff (`lastModified > cutoff`), so
+    /// the cutoff means "the last timestamp I have". The default.
+    Exclusive,
+    /// Also fetch a dump modified exactly at the cutoff (`lastModified >= cutoff`), so
+    /// the cutoff means "the first timestamp I still want".
+    Inclusive,
+}
+
+impl CutoffMode {
+    /// Whether a dump last modified at `last_modified` should be fetched.
+    pub fn wants(&self, last_modified: u64, cutoff: u64) -> bool {
+        match *self {
+            CutoffMode::Exclusive => last_modified > cutoff,
+            CutoffMode::Inclusive => last_modified >= cutoff,
+        }
+    }
+}
+
+impl Default for CutoffMode {
+    fn default() -> CutoffMode {
+        CutoffMode::Exclusive
+    }
+}
+
+/// The backoff state of a single call's retry loop. Every call starts with a
+/// fresh one so a blip in one call doesn't slow down the next.
+struct Backoff {
+    next: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    fn new(config: &RetryConfig) -> Backoff {
+        Backoff {
+            next: config.initial_backoff,
+            max: config.max_backoff,
+        }
+    }
+
+    /// Returns how long to wait before the next retry, and grows the wait for the one after.
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = cmp::min(self.next * 2, self.max);
+        delay
+    }
+}
+
+pub struct BattleNetApiClient {
+    pub token: String,
+    region: Region,
+    render_host: String,
+    transport: Box<Transport>,
+    clock: Arc<Clock>,
+    tt: ThreadThrottler,
+    retry_config: RetryConfig,
+    retry_policy: Box<RetryPolicy>,
+    failure_summary_every: u32,
+    realm_cache_ttl: Duration,
+    realm_cache: Mutex<Option<(Instant, Vec<RealmInfo>)>>,
+    validators: Mutex<HashMap<String, Validators>>,
+    item_cache: Arc<ItemCache>,
+    icon_cache_dir: PathBuf,
+    content_decoders: Vec<Box<ContentDecoder>>,
+    before_request: Option<Box<RequestHook>>,
+    field_filtering: bool,
+    metrics: Arc<ClientMetrics>,
+    max_requests: Option<u64>,
+    cutoff_mode: CutoffMode,
+    quiet: bool,
+}
+
+/// Used to construct a BattleNetApiClient with non-default settings.
+pub struct BattleNetApiClientBuilder {
+    token: String,
+    region: Region,
+    render_host: Option<String>,
+    transport: Option<Box<Transport>>,
+    clock: Option<Arc<Clock>>,
+    retry_config: RetryConfig,
+    retry_policy: Option<Box<RetryPolicy>>,
+    failure_summary_every: u32,
+    fail_fast: bool,
+    realm_cache_ttl: Duration,
+    item_cache: Option<Arc<ItemCache>>,
+    throttle_mode: ThrottleMode,
+    icon_cache_dir: Option<PathBuf>,
+    content_decoders: Vec<Box<ContentDecoder>>,
+    before_request: Option<Box<RequestHook>>,
+    field_filtering: bool,
+    metrics: Option<Arc<ClientMetrics>>,
+    max_requests: Option<u64>,
+    cutoff_mode: CutoffMode,
+    quiet: bool,
+}
+
+impl BattleNetApiClientBuilder {
+    pub fn new(token: &str) -> BattleNetApiClientBuilder {
+        BattleNetApiClientBuilder {
+            token: token.to_owned(),
+            region: Region::Us,
+            render_host: None,
+            transport: None,
+            clock: None,
+            retry_config: RetryConfig::default(),
+            retry_policy: None,
+            failure_summary_every: DEFAULT_FAILURE_SUMMARY_EVERY,
+            fail_fast: false,
+            realm_cache_ttl: Duration::from_secs(DEFAULT_REALM_CACHE_TTL_SECS),
+            item_cache: None,
+            throttle_mode: ThrottleMode::SlidingWindow,
+            icon_cache_dir: None,
+            content_decoders: Vec::new(),
+            before_request: None,
+            field_filtering: false,
+            metrics: None,
+            max_requests: None,
+            cutoff_mode: CutoffMode::default(),
+            quiet: false,
+        }
+    }
+
+    /// Sets the region whose API (and render host) the client will talk to.
+    /// Defaults to `Region::Us`.
+    pub fn region(mut self, region: Region) -> BattleNetApiClientBuilder {
+        self.region = region;
+        self
+    }
+
+    /// Overrides the host used for icon URLs, e.g. to use a specific CDN.
+    /// Defaults to the region's render host.
+    pub fn render_host(mut self, host: &str) -> BattleNetApiClientBuilder {
+        self.render_host = Some(host.to_owned());
+        self
+    }
+
+    /// Has the client send its requests through `transport` instead of a hyper client.
+    pub fn transport(mut self, transport: Box<Transport>) -> BattleNetApiClientBuilder {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Has the client tell the time (for cache TTLs, backoff, deadlines and throttle
+    /// waits) with `clock` instead of the system clock. Mostly useful in tests, with a `ManualClock`.
+    pub fn clock(mut self, clock: Arc<Clock>) -> BattleNetApiClientBuilder {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Sets how calls back off between retries.
+    pub fn retry_config(mut self, retry_config: RetryConfig) -> BattleNetApiClientBuilder {
+        self.retry_config = retry_config;
+        self
+    }
+
+    /// Has `policy` decide which failures are retried, instead of the
+    /// `DefaultRetryPolicy` built from `retry_config` and `fail_fast`. The
+    /// `max_retries` and `fail_fast` settings then have no effect.
+    pub fn retry_policy(mut self, policy: Box<RetryPolicy>) -> BattleNetApiClientBuilder {
+        self.retry_policy = Some(policy);
+        self
+    }
+
+    /// When the same error repeats during retries, only the first occurrence is
+    /// logged, followed by a summary line every `n` repeats.
+    pub fn failure_summary_every(mut self, n: u32) -> BattleNetApiClientBuilder {
+        assert!(n > 0, "Summary interval must be positive.");
+        self.failure_summary_every = n;
+        self
+    }
+
+    /// In fail-fast mode a response that fails to decode is returned as a
+    /// `DecodeError` immediately instead of being retried. Useful for debugging.
+    pub fn fail_fast(mut self, fail_fast: bool) -> BattleNetApiClientBuilder {
+        self.fail_fast = fail_fast;
+        self
+    }
+
+    /// Sets how long the realm list is cached before `get_realms` fetches it again.
+    /// Defaults to an hour.
+    pub fn realm_cache_ttl(mut self, ttl: Duration) -> BattleNetApiClientBuilder {
+        self.realm_cache_ttl = ttl;
+        self
+    }
+
+    /// Has the client use (and fill) `item_cache` for item info. Pass the same cache
+    /// to several clients to share item info between them. By default every client
+    /// gets its own cache.
+    pub fn item_cache(mut self, item_cache: Arc<ItemCache>) -> BattleNetApiClientBuilder {
+        self.item_cache = Some(item_cache);
+        self
+    }
+
+    /// Sets how the client's 100 requests per second are spread out. The default
+    /// sliding window allows short bursts; `ThrottleMode::Smoothed` spaces requests
+    /// 10ms apart.
+    pub fn throttle_mode(mut self, mode: ThrottleMode) -> BattleNetApiClientBuilder {
+        self.throttle_mode = mode;
+        self
+    }
+
+    /// Sets the directory `get_item_icon_bytes` caches icons in. Defaults to
+    /// `blood-money-icons` in the system temp directory.
+    pub fn icon_cache_dir<P: Into<PathBuf>>(mut self, dir: P) -> BattleNetApiClientBuilder {
+        self.icon_cache_dir = Some(dir.into());
+        self
+    }
+
+    /// Registers a decoder for another response format. Decoders are consulted in
+    /// the order they were added, before the default JSON one.
+    pub fn content_decoder(mut self, decoder: Box<ContentDecoder>) -> BattleNetApiClientBuilder {
+        self.content_decoders.push(decoder);
+        self
+    }
+
+    /// Sets a hook to run before every API request is sent. See `RequestHook`.
+    pub fn before_request(mut self, hook: Box<RequestHook>) -> BattleNetApiClientBuilder {
+        self.before_request = Some(hook);
+        self
+    }
+
+    /// Has item info and realm status requests ask for only the fields the crate
+    /// reads, which makes resolving thousands of items noticeably cheaper. Off by
+    /// default since not every endpoint supports it. Endpoints that ignore the
+    /// parameter just send the full response, which decodes the same.
+    pub fn field_filtering(mut self, field_filtering: bool) -> BattleNetApiClientBuilder {
+        self.field_filtering = field_filtering;
+        self
+    }
+
+    /// Has the client count into `metrics`, e.g. one created with
+    /// `ClientMetrics::with_prometheus`, or one shared with other clients. By
+    /// default every client gets its own.
+    pub fn metrics(mut self, metrics: Arc<ClientMetrics>) -> BattleNetApiClientBuilder {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Caps the number of API requests the client sends over its lifetime, retries
+    /// included. Once they're used up every call fails with `QuotaExhausted`. The
+    /// cap is checked against the client's `ClientMetrics` request count, so clients
+    /// sharing metrics share the cap too. Unlimited by default.
+    pub fn max_requests(mut self, max_requests: u64) -> BattleNetApiClientBuilder {
+        self.max_requests = Some(max_requests);
+        self
+    }
+
+    /// Sets how auction dump timestamps are compared with cutoffs. Defaults to
+    /// `CutoffMode::Exclusive`.
+    pub fn cutoff_mode(mut self, mode: CutoffMode) -> BattleNetApiClientBuilder {
+        self.cutoff_mode = mode;
+        self
+    }
+
+    /// Stops the client printing anything, e.g. the retry log lines, for programs
+    /// that handle everything through returned errors and `metrics`. Off by default.
+    pub fn quiet(mut self, quiet: bool) -> BattleNetApiClientBuilder {
+        self.quiet = quiet;
+        self
+    }
+
+    pub fn build(self) -> BattleNetApiClient {
+        let region = self.region;
+        let default_policy = DefaultRetryPolicy { max_retries: self.retry_config.max_retries, fail_fast: self.fail_fast };
+        let clock = self.clock.unwrap_or_else(|| Arc::new(SystemClock));
+        BattleNetApiClient {
+            token: self.token,
+            region: region,
+            render_host: self.render_host.unwrap_or_else(|| region.render_host().to_owned()),
+            transport: self.transport.unwrap_or_else(|| Box::new(HyperTransport::new())),
+            tt: ThreadThrottler::with_clock(100, Duration::new(1, 0), self.throttle_mode, clock.clone()),
+            clock: clock,
+            retry_config: self.retry_config,
+            retry_policy: self.retry_policy.unwrap_or_else(|| Box::new(default_policy)),
+            failure_summary_every: self.failure_summary_every,
+            realm_cache_ttl: self.realm_cache_ttl,
+            realm_cache: Mutex::new(None),
+            validators: Mutex::new(HashMap::new()),
+            item_cache: self.item_cache.unwrap_or_else(|| Arc::new(ItemCache::new())),
+            icon_cache_dir: self.icon_cache_dir.unwrap_or_else(|| env::temp_dir().join("blood-money-icons")),
+            content_decoders: self.content_decoders,
+            before_request: self.before_request,
+            field_filtering: self.field_filtering,
+            metrics: self.metrics.unwrap_or_else(|| Arc::new(ClientMetrics::new())),
+            max_requests: self.max_requests,
+            cutoff_mode: self.cutoff_mode,
+            quiet: self.quiet,
+        }
+    }
+}
+
+/// Coalesces runs of identical failures in a retry loop so that an outage
+/// doesn't fill the log with the same line over and over.
+/// Each method returns the line that should be logged, if any.
+struct FailureLog {
+    summary_every: u32,
+    last_error: Option<String>,
+    suppressed: u32,
+}
+
+impl FailureLog {
+    fn new(summary_every: u32) -> FailureLog {
+        FailureLog {
+            summary_every: summary_every,
+            last_error: None,
+            suppressed: 0,
+        }
+    }
+
+    /// Records a failure on attempt number `retries`.
+    fn failure(&mut self, task: &str, error: &str, retries: u32) -> Option<String> {
+        if self.last_error.as_ref().map_or(false, |last| last == error) {
+            self.suppressed += 1;
+            if self.suppressed % self.summary_every == 0 {
+                return Some(format!("Error downloading {}: suppressed {} identical errors ({}). Retry {}.",
+                                    task, self.suppressed, error, retries));
+            }
+            return None;
+        }
+        let line = match self.suppressed {
+            0 => format!("Error downloading {}: {}. Retry {}.", task, error, retries),
+            n => format!("Error downloading {}: {}. Retry {}. (Suppressed {} identical errors before this.)",
+                         task, error, retries, n),
+        };
+        self.last_error = Some(error.to_owned());
+        self.suppressed = 0;
+        Some(line)
+    }
+
+    /// Records that the call finally succeeded on attempt number `retries`.
+    fn success(&self, task: &str, retries: u32) -> Option<String> {
+        match self.last_error {
+            None => None,
+            Some(_) => Some(format!("Downloaded {} after {} retries.", task, retries - 1)),
+        }
+    }
+}
+
+impl BattleNetApiClient {
+    pub fn new(token: &str) -> BattleNetApiClient {
+        BattleNetApiClientBuilder::new(token).build()
+    }
+
+    pub fn builder(token: &str) -> BattleNetApiClientBuilder {
+        BattleNetApiClientBuilder::new(token)
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// The client's counters as of now.
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    /// How many more requests the client may send before hitting its `max_requests`,
+    /// or None if it has no cap.
+    pub fn remaining_requests(&self) -> Option<u64> {
+        self.max_requests.map(|max_requests| max_requests.saturating_sub(self.metrics.snapshot().requests))
+    }
+
+    /// Builds the URL for an API path, e.g. `realm/status`, in the client's region.
+    fn api_url(&self, path: &str) -> String {
+        self.localized_api_url(path, "en_US")
+    }
+
+    /// Builds the URL for a game data API path, e.g. `connected-realm/11`, in the
+    /// client's region. `namespace` is the namespace's prefix, e.g. `dynamic`.
+    /// Unlike the community API these endpoints need the client's token to be an
+    /// OAuth access token rather than an API key.
+    fn data_url(&self, path: &str, namespace: &str) -> String {
+        format!("https://{}/data/wow/{}?namespace={}-{}&locale=en_US&access_token={}",
+                self.region.data_host(), path, namespace, self.region.namespace_suffix(), self.token)
+    }
+
+    /// Like `api_url` but asks for results in `locale`.
+    fn localized_api_url(&self, path: &str, locale: &str) -> String {
+        format!("https://{}/wow/{}?locale={}&apikey={}", self.region.api_host(), path, locale, self.token)
+    }
+
+    /// Whether the client was built `quiet`. Code driving a client should stay
+    /// quiet along with it.
+    pub fn is_quiet(&self) -> bool {
+        self.quiet
+    }
+
+    /// Prints an informational line unless the client is quiet.
+    fn log(&self, line: &str) {
+        if !self.quiet {
+            println!("{}", line);
+        }
+    }
+
+    /// Counts a request about to be sent, or fails with `QuotaExhausted` if the client
+    /// has used up its `max_requests`.
+    fn count_request(&self) -> Result<(), BattleNetApiError> {
+        match self.max_requests {
+            Some(max_requests) => if !self.metrics.try_record_request(max_requests) {
+                return Err(BattleNetApiError::QuotaExhausted);
+            },
+            None => self.metrics.record_request(),
+        }
+        Ok(())
+    }
+
+    /// Gets a request ready to send: runs the `before_request` hook on it, waits for
+    /// the throttle and counts it. Returns `TimedOut` without counting anything if
+    /// `deadline` has passed by the time the throttle lets it through. Every API
+    /// request goes through here.
+    fn prepare_request(&self, url: &str, headers: &mut Headers, deadline: Option<Instant>) -> Result<(), BattleNetApiError> {
+        if let Some(ref hook) = self.before_request {
+            hook(url, headers);
+        }
+        self.tt.pass_through_or_block();
+        if deadline.map_or(false, |deadline| self.clock.now() >= deadline) {
+            return Err(BattleNetApiError::TimedOut);
+        }
+        self.count_request()
+    }
+
+    /// Adds a `fields` parameter asking for just `fields` to `url` if field filtering
+    /// is on.
+    fn filtered_url(&self, url: String, fields: &str) -> String {
+        if self.field_filtering {
+            format!("{}&fields={}", url, fields)
+        } else {
+            url
+        }
+    }
+
+    /// Builds the URL of an item's icon using the client's render host.
+    pub fn icon_url(&self, item: &ItemInfo, size: IconSize) -> String {
+        item.icon_url(&self.render_host, size)
+    }
+
+    /// Returns the image bytes of an item's icon, downloading it from the render host
+    /// on the first call and reading it from the icon cache directory after that.
+    /// Icons are public, so this is a plain GET that carries none of our credentials
+    /// and isn't counted against the API throttle or `max_requests`, though the
+    /// `before_request` hook still sees it. Failing to write the cache isn't
+    /// an error; the icon just gets downloaded again next time.
+    pub fn get_item_icon_bytes(&self, item: &ItemInfo, size: IconSize) -> Result<Vec<u8>, BattleNetApiError> {
+        let path = self.icon_cache_dir.join(format!("{}-{}.jpg", item.icon, size.pixels()));
+        if let Ok(mut file) = File::open(&path) {
+            let mut bytes = Vec::new();
+            if file.read_to_end(&mut bytes).is_ok() {
+                return Ok(bytes);
+            }
+        }
+        let url = self.icon_url(item, size);
+        let mut headers = Headers::new();
+        if let Some(ref hook) = self.before_request {
+            hook(&url, &mut headers);
+        }
+        let res = self.transport.get(&url, headers)?;
+        if res.status != hyper::Ok {
+            return Err(BattleNetApiError::HttpStatus(res.status, None));
+        }
+        let _ = fs::create_dir_all(&self.icon_cache_dir)
+            .and_then(|_| File::create(&path))
+            .and_then(|mut file| file.write_all(&res.body));
+        Ok(res.body)
+    }
+
+    /// Try to retrieve something from the Blizzard API. Failed attempts are retried,
+    /// backing off between them, for as long as the client's `RetryPolicy` says to.
+    /// `task` will be used for error messages.
+    fn make_blizzard_api_call<T: FromJson>(&self, url: &str, task: &str) -> Result<T, BattleNetApiError> {
+        self.retry_blizzard_api_call(url, task, false, None, &decode_json::<T>, None)
+    }
+
+    /// Like `make_blizzard_api_call` but makes the request conditional on the resource
+    /// having changed since we last fetched `url`. Returns None if it hasn't.
+    /// `filter`, if given, is applied to the body before it's handed to `decode`.
+    fn make_conditional_blizzard_api_call<T>(&self, url: &str, task: &str, filter: Option<&BodyFilter>, decode: &BodyDecoder<T>,
+                                             deadline: Option<Instant>) -> Result<Option<T>, BattleNetApiError> {
+        match self.retry_blizzard_api_call(url, task, true, filter, decode, deadline) {
+            Ok(obj) => Ok(Some(obj)),
+            Err(BattleNetApiError::NotModified) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The retry loop shared by the `make_*_api_call` functions. With a `deadline`
+    /// the loop gives up with `TimedOut` rather than start an attempt or a backoff
+    /// that would end after it.
+    ///
+    /// With the `tracing` feature each call is a `blizzard_api_call` span recording
+    /// the task, url, number of attempts, bytes in the last response and how long
+    /// the whole call took in milliseconds.
+    fn retry_blizzard_api_call<T>(&self, url: &str, task: &str, conditional: bool, filter: Option<&BodyFilter>,
+                                  decode: &BodyDecoder<T>, deadline: Option<Instant>) -> Result<T, BattleNetApiError> {
+        self.retry_blizzard_api_call_with_policy(url, task, conditional, filter, decode, deadline, &*self.retry_policy)
+    }
+
+    /// Like `retry_blizzard_api_call` but consults `policy` instead of the client's
+    /// `RetryPolicy`.
+    fn retry_blizzard_api_call_with_policy<T>(&self, url: &str, task: &str, conditional: bool, filter: Option<&BodyFilter>,
+                                              decode: &BodyDecoder<T>, deadline: Option<Instant>,
+                                              policy: &RetryPolicy) -> Result<T, BattleNetApiError> {
+        #[cfg(feature = "tracing")]
+        let span = info_span!("blizzard_api_call", task = task, url = url, attempts = tracing::field::Empty,
+                              bytes = tracing::field::Empty, elapsed_ms = tracing::field::Empty);
+        #[cfg(feature = "tracing")]
+        let _entered = span.enter();
+        #[cfg(feature = "tracing")]
+        let start = self.clock.now();
+        let result = self.retry_loop(url, task, conditional, filter, decode, deadline, policy);
+        #[cfg(feature = "tracing")]
+        span.record("elapsed_ms", &duration_ms(self.clock.now() - start));
+        result
+    }
+
+    fn retry_loop<T>(&self, url: &str, task: &str, conditional: bool, filter: Option<&BodyFilter>,
+                     decode: &BodyDecoder<T>, deadline: Option<Instant>, policy: &RetryPolicy) -> Result<T, BattleNetApiError> {
+        let mut retries = 0;
+        let mut failure_log = FailureLog::new(self.failure_summary_every);
+        let mut backoff = Backoff::new(&self.retry_config);
+
+        loop {
+            retries += 1;
+            #[cfg(feature = "tracing")]
+            tracing::Span::current().record("attempts", &retries);
+            match self.try_blizzard_api_call(url, conditional, filter, decode, deadline) {
+                Ok(obj) => {
+                    self.metrics.record_success(self.clock.system_time());
+                    if let Some(line) = failure_log.success(task, retries) {
+                        self.log(&line);
+                    }
+                    return Ok(obj);
+                },
+                Err(BattleNetApiError::NotModified) => return Err(BattleNetApiError::NotModified),
+                Err(BattleNetApiError::TimedOut) => return Err(BattleNetApiError::TimedOut),
+                Err(BattleNetApiError::QuotaExhausted) => return Err(BattleNetApiError::QuotaExhausted),
+                Err(e) => {
+                    let status = match e {
+                        BattleNetApiError::HttpStatus(status, _) => Some(status),
+                        _ => None,
+                    };
+                    if let Some(line) = failure_log.failure(task, &e.to_string(), retries) {
+                        self.log(&line);
+                    }
+                    if policy.should_retry(status, retries, &e) == RetryDecision::GiveUp {
+                        return Err(e);
+                    }
+                    let delay = backoff.next_delay();
+                    if deadline.map_or(false, |deadline| self.clock.now() + delay >= deadline) {
+                        return Err(BattleNetApiError::TimedOut);
+                    }
+                    self.metrics.record_retry();
+                    self.clock.sleep(delay);
+                },
+            }
+        }
+    }
+
+    /// Makes a single attempt at retrieving and decoding something from the Blizzard API.
+    /// If `conditional` is set, the validators from the last fetch of `url` are sent
+    /// along and a `NotModified` error is returned if Blizzard says nothing changed.
+    /// `filter`, if given, is applied to the body before it's handed to `decode`.
+    /// Returns `TimedOut` without sending anything if `deadline` has passed by the
+    /// time the throttle lets the request through. A request already in flight
+    /// isn't interrupted.
+    fn try_blizzard_api_call<T>(&self, url: &str, conditional: bool, filter: Option<&BodyFilter>, decode: &BodyDecoder<T>,
+                                deadline: Option<Instant>) -> Result<T, BattleNetApiError> {
+        let mut headers = Headers::new();
+        if conditional {
+            if let Some(validators) = self.validators.lock().unwrap().get(url) {
+                if let Some(ref etag) = validators.etag {
+                    headers.set(IfNoneMatch::Items(vec![etag.clone()]));
+                }
+                if let Some(ref last_modified) = validators.last_modified {
+                    headers.set(IfModifiedSince(last_modified.clone()));
+                }
+            }
+        }
+        self.prepare_request(url, &mut headers, deadline)?;
+        let res = self.transport.get(url, headers)?;
+        self.metrics.record_bytes(res.body.len());
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("bytes", &(res.body.len() as u64));
+        if res.status == StatusCode::NotModified {
+            return Err(BattleNetApiError::NotModified);
+        }
+        if res.status != hyper::Ok {
+            let error = decode_json::<BattleNetError>(&String::from_utf8_lossy(&res.body)).ok();
+            return Err(BattleNetApiError::HttpStatus(res.status, error));
+        }
+        let content_type = res.headers.get::<ContentType>().map(|content_type| content_type.0.clone());
+        // An HTML error page isn't a body any decoder could make sense of, whatever
+        // its encoding, so report it before choosing one.
+        if let Some(Mime(TopLevel::Text, SubLevel::Html, _)) = content_type {
+            return Err(BattleNetApiError::UpstreamHtml);
+        }
+        let validators = Validators {
+            etag: res.headers.get::<ETag>().map(|etag| etag.0.clone()),
+            last_modified: res.headers.get::<LastModified>().map(|last_modified| last_modified.0.clone()),
+        };
+        let default_decoder = JsonContentDecoder;
+        let content_decoder: &ContentDecoder = content_type.as_ref()
+            .and_then(|content_type| self.content_decoders.iter().find(|decoder| decoder.handles(content_type)))
+            .map(|decoder| &**decoder)
+            .unwrap_or(&default_decoder);
+        let s = content_decoder.decode_content(res.body)?;
+        let obj = match decode_body(&s, filter, decode) {
+            Ok(obj) => obj,
+            Err(e) => {
+                if let BattleNetApiError::DecodeError(..) = e {
+                    self.metrics.record_decode_error();
+                }
+                return Err(e);
+            },
+        };
+        // Only remember validators once we've actually got the data, otherwise a
+        // failed decode would turn every retry into a 304.
+        if conditional && (validators.etag.is_some() || validators.last_modified.is_some()) {
+            self.validators.lock().unwrap().insert(url.to_owned(), validators);
+        }
+        Ok(obj)
+    }
+
+    /// Fetches and decodes any JSON document from Blizzard, with the client's usual
+    /// throttling and retries. `filter`, if given, is applied to the body before decoding,
+    /// e.g. `Some(&strip_owners as &BodyFilter)`.
+    pub fn get_json<T: FromJson>(&self, url: &str, filter: Option<&BodyFilter>) -> Result<T, BattleNetApiError> {
+        self.retry_blizzard_api_call(url, url, false, filter, &decode_json::<T>, None)
+    }
+
+    /// Returns the list of realms. The list is cached, so this only hits the
+    /// Blizzard API if the cached copy is older than the client's realm cache TTL.
+    pub fn get_realms(&self) -> Result<Vec<RealmInfo>, BattleNetApiError> {
+        {
+            let cache = self.realm_cache.lock().unwrap();
+            if let Some((ref fetched_at, ref realms)) = *cache {
+                if self.clock.now() - *fetched_at < self.realm_cache_ttl {
+                    return Ok(realms.clone());
+                }
+            }
+        }
+        self.refresh_realms()
+    }
+
+    /// Downloads a fresh list of realms from the Blizzard API, replacing the cached one.
+    pub fn refresh_realms(&self) -> Result<Vec<RealmInfo>, BattleNetApiError> {
+        let realm_data: BTreeMap<String, Vec<RealmInfo>> =
+            self.make_blizzard_api_call(&self.filtered_url(self.api_url("realm/status"), REALM_FIELDS), "realm status")?;
+        let realms = realms_from_status(realm_data)?;
+        *self.realm_cache.lock().unwrap() = Some((self.clock.now(), realms.clone()));
+        Ok(realms)
+    }
+
+    /// Looks up a realm by its slug, using the cached realm list where possible.
+    pub fn find_realm(&self, slug: &str) -> Result<Option<RealmInfo>, BattleNetApiError> {
+        Ok(self.get_realms()?.into_iter().find(|realm| realm.slug == slug))
+    }
+
+    /// Lists every data file making up a realm's current auction dump as
+    /// (last modified, URL) pairs. Usually there's just one.
+    pub fn get_auction_data_files(&self, realm_slug: &str) -> Result<Vec<(u64, String)>, BattleNetApiError> {
+        self.auction_data_files(realm_slug, None)
+    }
+
+    fn auction_data_files(&self, realm_slug: &str, deadline: Option<Instant>) -> Result<Vec<(u64, String)>, BattleNetApiError> {
+        let auction_data_reply: AuctionDataReply =
+            self.retry_blizzard_api_call(
+                &self.api_url(&format!("auction/data/{}", realm_slug)),
+                &format!("auction data for {}", realm_slug),
+                false, None, &decode_json::<AuctionDataReply>, deadline
+            )?;
+        Ok(auction_data_reply.files.into_iter().map(|pointer| (pointer.lastModified, pointer.url)).collect())
+    }
+
+    /// Asks where a realm's current auction dump is without downloading it, or None
+    /// if Blizzard didn't point us at a file. This is the cheap first half of
+    /// `get_auction_listings`; `download_auction_data` is the expensive second half.
+    pub fn resolve_auction_pointer(&self, realm_slug: &str) -> Result<Option<AuctionDataPointer>, BattleNetApiError> {
+        self.auction_pointer(realm_slug, None)
+    }
+
+    fn auction_pointer(&self, realm_slug: &str, deadline: Option<Instant>)
+            -> Result<Option<AuctionDataPointer>, BattleNetApiError> {
+        let files = self.auction_data_files(realm_slug, deadline)?;
+        Ok(files.iter().map(|&(last_modified, _)| last_modified).max().map(|last_modified| AuctionDataPointer {
+            realm_slug: realm_slug.to_owned(),
+            last_modified: last_modified,
+            files: files.clone(),
+        }))
+    }
+
+    /// Downloads and combines every file of the dump `pointer` points at. Unlike
+    /// `get_auction_listings` this always downloads, whatever the cutoff or the
+    /// CDN's validators say.
+    pub fn download_auction_data(&self, pointer: &AuctionDataPointer) -> Result<Vec<AuctionListing>, BattleNetApiError> {
+        let task = format!("auction listings for {}", pointer.realm_slug);
+        let mut auctions = Vec::new();
+        for &(_, ref url) in &pointer.files {
+            auctions.extend(self.auction_data_from_url(url, &task)?);
+        }
+        Ok(auctions)
+    }
+
+    /// Downloads a single auction data file straight from its URL, e.g. one saved
+    /// from an old `AuctionDataPointer`, without asking where the realm's current
+    /// dump is. Blizzard keeps old files around for a while, so this can re-fetch
+    /// exactly the dump an earlier run saw. Decoded the same way as any other dump.
+    pub fn download_auction_data_from_url(&self, url: &str) -> Result<Vec<AuctionListing>, BattleNetApiError> {
+        self.auction_data_from_url(url, &format!("auction data file {}", url))
+    }
+
+    fn auction_data_from_url(&self, url: &str, task: &str) -> Result<Vec<AuctionListing>, BattleNetApiError> {
+        self.retry_blizzard_api_call(url, task, false, Some(&strip_owners as &BodyFilter), &decode_auction_listings, None)
+    }
+
+    /// Resolves the data-file URL for a realm's current auction dump. Returns the dump's
+    /// last modified time and its URL, or None if Blizzard didn't point us at a file.
+    /// If the dump is split over several files this is the newest one.
+    pub fn get_auction_data_url(&self, realm_slug: &str) -> Result<Option<(u64, String)>, BattleNetApiError> {
+        Ok(self.get_auction_data_files(realm_slug)?.into_iter().max_by_key(|&(last_modified, _)| last_modified))
+    }
+
+    /// Returns the size in bytes of a realm's current auction dump, summed over all of
+    /// its files, without downloading it. Each file costs a HEAD request, which counts
+    /// against `max_requests` like any other. Returns None
+    /// if Blizzard didn't point us at a file or didn't say how big one is.
+    pub fn get_auction_data_size(&self, realm_slug: &str) -> Result<Option<u64>, BattleNetApiError> {
+        let files = self.get_auction_data_files(realm_slug)?;
+        if files.is_empty() {
+            return Ok(None);
+        }
+        let mut total = 0;
+        for (_, url) in files {
+            let mut headers = Headers::new();
+            self.prepare_request(&url, &mut headers, None)?;
+            let res = self.transport.head(&url, headers)?;
+            if res.status != hyper::Ok {
+                return Err(BattleNetApiError::HttpStatus(res.status, None));
+            }
+            match res.headers.get::<ContentLength>() {
+                Some(&ContentLength(length)) => total += length,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(total))
+    }
+
+    /// Copies a realm's raw auction dump into `writer` as it downloads, without
+    /// decoding it, stripping owners or holding it all in memory, and returns its
+    /// last modified time. Meant for archiving. If the dump is split over several
+    /// files only the newest is copied (see `get_auction_data_url`). The copy isn't
+    /// retried since part of it may already be written, so throw away whatever a
+    /// failed call wrote.
+    pub fn stream_auction_data_to<W: Write>(&self, realm_slug: &str, mut writer: W) -> Result<u64, BattleNetApiError> {
+        let (last_modified, url) = match self.get_auction_data_url(realm_slug)? {
+            Some(pointer) => pointer,
+            None => return Err(BattleNetApiError::MalformedResponse(
+                format!("no auction data file for {}", realm_slug))),
+        };
+        let mut headers = Headers::new();
+        self.prepare_request(&url, &mut headers, None)?;
+        let (status, copied) = self.transport.get_to(&url, headers, &mut writer)?;
+        self.metrics.record_bytes(copied as usize);
+        if status != StatusCode::Ok {
+            return Err(BattleNetApiError::HttpStatus(status, None));
+        }
+        writer.flush().map_err(BattleNetApiError::ReadError)?;
+        Ok(last_modified)
+    }
+
+    /// Returns when a realm's auction dump was last modified without downloading it,
+    /// or None if Blizzard didn't point us at a file.
+    pub fn get_auction_last_modified(&self, realm_slug: &str) -> Result<Option<u64>, BattleNetApiError> {
+        Ok(self.get_auction_data_url(realm_slug)?.map(|(last_modified, _)| last_modified))
+    }
+
+    /// Like the free `is_stale` but as of the client's clock.
+    pub fn is_stale(&self, last_modified: u64, max_age: Duration) -> bool {
+        is_stale_at(last_modified, max_age, self.clock.system_time())
+    }
+
+    /// Downloads the auction listings for the specified realm, or None if the listings haven't
+    /// been updated since `cutoff`. If the dump is split over several files they're all
+    /// downloaded and combined, and the newest file's timestamp is returned.
+    /// Data files are fetched conditionally, so this also returns None if Blizzard's CDN
+    /// says every file is unchanged since our last download.
+    pub fn get_auction_listings(&self, realm_slug: &str, cutoff: u64) -> Result<Option<(u64, Vec<AuctionListing>)>, BattleNetApiError> {
+        self.auction_listings(realm_slug, cutoff, None)
+    }
+
+    /// Like `get_auction_listings`, but gives up with a `TimedOut` error rather than
+    /// keep going past `deadline` (as told by the client's clock). The deadline
+    /// covers the whole call: the pointer request, every data file, throttle waits
+    /// and the backoff between retries. It's checked before each request and each
+    /// backoff, so a download already under way when the deadline passes still
+    /// finishes, after which the call times out.
+    pub fn get_auction_listings_deadline(&self, realm_slug: &str, cutoff: u64, deadline: Instant)
+            -> Result<Option<(u64, Vec<AuctionListing>)>, BattleNetApiError> {
+        let listings = self.auction_listings(realm_slug, cutoff, Some(deadline))?;
+        if self.clock.now() > deadline {
+            return Err(BattleNetApiError::TimedOut);
+        }
+        Ok(listings)
+    }
+
+    fn auction_listings(&self, realm_slug: &str, cutoff: u64, deadline: Option<Instant>)
+            -> Result<Option<(u64, Vec<AuctionListing>)>, BattleNetApiError> {
+        // The pointer and data file calls inside get their own spans.
+        #[cfg(feature = "tracing")]
+        let span = info_span!("auction_listings", realm = realm_slug, cutoff = cutoff);
+        #[cfg(feature = "tracing")]
+        let _entered = span.enter();
+        let pointer = match self.auction_pointer(realm_slug, deadline)? {
+            Some(pointer) => pointer,
+            None => return Ok(None),
+        };
+        if !self.cutoff_mode.wants(pointer.last_modified, cutoff) {
+            return Ok(None)
+        }
+        let task = format!("auction listings for {}", realm_slug);
+        let mut replies: Vec<Option<Vec<AuctionListing>>> = Vec::new();
+        for &(_, ref url) in &pointer.files {
+            replies.push(self.make_conditional_blizzard_api_call(url, &task, Some(&strip_owners as &BodyFilter),
+                                                                 &decode_auction_listings, deadline)?);
+        }
+        if replies.iter().all(|reply| reply.is_none()) {
+            return Ok(None);
+        }
+        // Some files changed and some didn't. We need all of them to put the dump
+        // together so fetch the unchanged ones again.
+        let mut auctions = Vec::new();
+        for (reply, &(_, ref url)) in replies.into_iter().zip(pointer.files.iter()) {
+            let reply = match reply {
+                Some(reply) => reply,
+                None => self.retry_blizzard_api_call(url, &task, false, Some(&strip_owners as &BodyFilter),
+                                                     &decode_auction_listings, deadline)?,
+            };
+            auctions.extend(reply);
+        }
+        Ok(Some((pointer.last_modified, auctions)))
+    }
+
+    /// Downloads auction listings for each of `slugs` (see `get_auction_listings`),
+    /// `threads` at a time. Fetches start in the order given, so put the realms that
+    /// matter most first. Results are returned in the same order, tagged with their slug.
+    pub fn get_auction_listings_for(&self, slugs: &[String], cutoff: u64, threads: u32)
+            -> Vec<(String, Result<Option<(u64, Vec<AuctionListing>)>, BattleNetApiError>)> {
+        let results = Mutex::new(Vec::with_capacity(slugs.len()));
+        let mut pool = Pool::new(threads);
+        pool.scoped(|scope| {
+            // The pool hands out jobs in the order they're queued.
+            for (index, slug) in slugs.iter().enumerate() {
+                let results = &results;
+                scope.execute(move || {
+                    let result = self.get_auction_listings(slug, cutoff);
+                    results.lock().unwrap().push((index, slug.clone(), result));
+                });
+            }
+        });
+        let mut results = results.into_inner().unwrap();
+        results.sort_by_key(|&(index, _, _)| index);
+        results.into_iter().map(|(_, slug, result)| (slug, result)).collect()
+    }
+
+    /// Downloads a connected realm group from the game data API. This is the
+    /// authoritative list of which realms share an auction house.
+    pub fn get_connected_realm(&self, id: u64) -> Result<ConnectedRealm, BattleNetApiError> {
+        let reply: ConnectedRealmReply = self.make_blizzard_api_call(
+            &self.data_url(&format!("connected-realm/{}", id), "dynamic"),
+            &format!("connected realm {}", id))?;
+        let slugs: Vec<String> = reply.realms.iter().map(|realm| realm.slug.clone()).collect();
+        let population = RealmPopulation::from_type(&reply.population.kind);
+        Ok(ConnectedRealm {
+            id: reply.id,
+            realms: reply.realms.into_iter().map(|realm| RealmInfo {
+                name: realm.name,
+                slug: realm.slug,
+                connected_realms: slugs.clone(),
+                population: Some(population.clone()),
+            }).collect(),
+            status: RealmStatus::from_type(&reply.status.kind),
+            population: population,
+            has_queue: reply.has_queue,
+        })
+    }
+
+    /// Downloads the region's current WoW Token price. Blizzard has no endpoint for
+    /// past prices, so to get a trend line collect these in a `TokenPriceHistory`.
+    pub fn get_wow_token_price(&self) -> Result<TokenPrice, BattleNetApiError> {
+        self.make_blizzard_api_call(&self.data_url("token/index", "dynamic"), "WoW Token price")
+    }
+
+    /// Lists every profession, without their skill tiers. See `get_profession`.
+    pub fn get_professions(&self) -> Result<Vec<NamedRef>, BattleNetApiError> {
+        let reply: ProfessionIndexReply =
+            self.make_blizzard_api_call(&self.data_url("profession/index", "static"), "profession index")?;
+        Ok(reply.professions)
+    }
+
+    /// Downloads a profession along with its skill tiers.
+    pub fn get_profession(&self, profession_id: u64) -> Result<Profession, BattleNetApiError> {
+        let reply: ProfessionReply = self.make_blizzard_api_call(
+            &self.data_url(&format!("profession/{}", profession_id), "static"),
+            &format!("profession {}", profession_id))?;
+        Ok(Profession {
+            id: reply.id,
+            name: reply.name,
+            skill_tiers: reply.skill_tiers.unwrap_or(Vec::new()),
+        })
+    }
+
+    /// Lists a profession's recipes, keyed by skill tier id. Costs one request for the
+    /// profession plus one per skill tier.
+    pub fn get_profession_recipes(&self, profession_id: u64) -> Result<BTreeMap<u64, Vec<NamedRef>>, BattleNetApiError> {
+        let mut recipes = BTreeMap::new();
+        for tier in self.get_profession(profession_id)?.skill_tiers {
+            let reply: SkillTierReply = self.make_blizzard_api_call(
+                &self.data_url(&format!("profession/{}/skill-tier/{}", profession_id, tier.id), "static"),
+                &format!("skill tier {} of profession {}", tier.id, profession_id))?;
+            let tier_recipes = reply.categories.unwrap_or(Vec::new()).into_iter()
+                .flat_map(|category| category.recipes.into_iter())
+                .collect();
+            recipes.insert(tier.id, tier_recipes);
+        }
+        Ok(recipes)
+    }
+
+    /// Searches item names (in en_US) through the game data API, returning page `page`
+    /// (starting from 1) with up to `page_size` results. See `search_all_items` to get
+    /// every page.
+    pub fn search_items(&self, name: &str, page: u32, page_size: u32)
+            -> Result<SearchPage<ItemSearchResult>, BattleNetApiError> {
+        let mut url = Url::parse(&self.data_url("search/item", "static")).expect("Bad search url.");
+        url.query_pairs_mut()
+            .append_pair("name.en_US", name)
+            .append_pair("orderby", "id")
+            .append_pair("_page", &page.to_string())
+            .append_pair("_pageSize", &page_size.to_string());
+        let reply: SearchReply<ItemSearchData> = self.make_blizzard_api_call(
+            url.as_str(), &format!("item search for {} (page {})", name, page))?;
+        Ok(SearchPage {
+            page: reply.page,
+            page_count: reply.pageCount,
+            results_capped: reply.resultCountCapped.unwrap_or(false),
+            results: reply.results.into_iter().map(|result| ItemSearchResult {
+                id: result.data.id,
+                name: result.data.name.en_US,
+            }).collect(),
+        })
+    }
+
+    /// Like `search_items` but walks every page and returns all the results. Each
+    /// page is a separate, throttled request.
+    pub fn search_all_items(&self, name: &str, page_size: u32) -> Result<Vec<ItemSearchResult>, BattleNetApiError> {
+        let mut results = Vec::new();
+        let mut page = 1;
+        loop {
+            let search_page = self.search_items(name, page, page_size)?;
+            results.extend(search_page.results);
+            if page >= search_page.page_count {
+                return Ok(results);
+            }
+            page += 1;
+        }
+    }
+
+    /// Helpler function to process a vec of RealmInfo's into vec's of slugs for
+    /// connected realms. Connected realms share an auction house. A realm with an
+    /// empty `connected_realms` list is treated as a group of its own. Slugs Blizzard
+    /// lists more than once in a group are kept only the first time.
+    pub fn process_connected_realms(realm_infos: &Vec<RealmInfo>) -> Vec<Vec<String>> {
+        let mut realm_sets: Vec<Vec<String>> = realm_infos.into_iter().map(|r|
+            if r.connected_realms.is_empty() {
+                vec![r.slug.clone()]
+            } else {
+                let mut group: Vec<String> = Vec::with_capacity(r.connected_realms.len());
+                for slug in &r.connected_realms {
+                    if !group.contains(slug) {
+                        group.push(slug.clone());
+                    }
+                }
+                group
+            }
+        ).collect();
+
+        // This dedup logic relies on the ordering within a connected realms list being the same
+        // for all realms in the list.
+        realm_sets.sort_by(|a, b| a.first().cmp(&b.first()));
+        realm_sets.dedup();
+        return realm_sets;
+    }
+
+    /// Returns info for an item, from the item cache if possible. Fails with
+    /// `ItemNotFound`, without retrying, if Blizzard has no such item.
+    pub fn get_item_info(&self, id: u64) -> Result<ItemInfo, BattleNetApiError> {
+        self.get_localized_item_info(id, "en_US")
+    }
+
+    /// Returns info for an item in `locale`, fetching and caching it on a cache miss.
+    fn get_localized_item_info(&self, id: u64, locale: &str) -> Result<ItemInfo, BattleNetApiError> {
+        if let Some(item_info) = self.item_cache.get(locale, id) {
+            return Ok(item_info);
+        }
+        let item_info = match self.retry_blizzard_api_call_with_policy(
+            &self.filtered_url(self.localized_api_url(&format!("item/{}", id), locale), ITEM_FIELDS),
+            &format!("{} item info", locale), false, None,
+            &|body: &str| decode_item_info(body, locale), None, &ItemRetryPolicy(&*self.retry_policy)) {
+            Err(BattleNetApiError::HttpStatus(StatusCode::NotFound, _)) => return Err(BattleNetApiError::ItemNotFound(id)),
+            result => result?,
+        };
+        self.item_cache.insert(locale, item_info.clone());
+        Ok(item_info)
+    }
+
+    /// Fetches an item's info once for each of `locales`, returning them keyed by locale.
+    /// Repeated locales are only fetched once. Note that the map iterates in locale order
+    /// rather than the order they were asked for.
+    pub fn get_item_info_multi(&self, id: u64, locales: &[&str]) -> Result<BTreeMap<String, ItemInfo>, BattleNetApiError> {
+        let mut item_infos = BTreeMap::new();
+        for &locale in locales {
+            if item_infos.contains_key(locale) {
+                continue;
+            }
+            let item_info = self.get_localized_item_info(id, locale)?;
+            item_infos.insert(locale.to_owned(), item_info);
+        }
+        Ok(item_infos)
+    }
+
+    /// Fetches info for many items, `threads` at a time. Ids that still fail after the
+    /// client's usual retries get up to `batch_retries` more passes, and each pass only
+    /// requests the ids that are still failing, so nothing that already succeeded is
+    /// requested or returned twice. Duplicate ids are only fetched once. Items Blizzard
+    /// doesn't have (see `ItemNotFound`) come back as an `ItemInfo::placeholder`.
+    pub fn get_item_infos(&self, ids: &[u64], threads: u32, batch_retries: u32)
+            -> BTreeMap<u64, Result<ItemInfo, BattleNetApiError>> {
+        let mut results = BTreeMap::new();
+        let mut pending: Vec<u64> = ids.to_vec();
+        pending.sort();
+        pending.dedup();
+        let mut pool = Pool::new(threads);
+        for _ in 0..(batch_retries as u64 + 1) {
+            let pass_results = Mutex::new(Vec::new());
+            pool.scoped(|scope| {
+                for &id in &pending {
+                    let pass_results = &pass_results;
+                    scope.execute(move || {
+                        let result = self.get_item_info(id);
+                        pass_results.lock().unwrap().push((id, result));
+                    });
+                }
+            });
+            pending.clear();
+            for (id, result) in pass_results.into_inner().unwrap() {
+                let result = match result {
+                    Err(BattleNetApiError::ItemNotFound(_)) => Ok(ItemInfo::placeholder(id)),
+                    Err(e) => {
+                        pending.push(id);
+                        Err(e)
+                    },
+                    ok => ok,
+                };
+                results.insert(id, result);
+            }
+            if pending.is_empty() {
+                break;
+            }
+        }
+        results
+    }
+
+    /// Pairs each listing with its item's name, fetching the info for every distinct
+    /// item that isn't cached yet. Items whose info can't be fetched are named
+    /// `"item:<id>"` so one bad id doesn't sink the rest.
+    pub fn resolve_names(&self, listings: &[AuctionListing]) -> Vec<(AuctionListing, String)> {
+        let item_infos = self.get_item_infos(&distinct_item_ids(listings), RESOLVE_NAMES_THREADS, 0);
+        listings.iter().map(|listing| {
+            let name = match item_infos.get(&listing.item) {
+                Some(&Ok(ref item_info)) => item_info.name.clone(),
+                _ => ItemInfo::placeholder(listing.item).name,
+            };
+            (listing.clone(), name)
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    use archive::{ArchiveConfig, NamingScheme};
+    use clock::ManualClock;
+    use metrics::MetricsSnapshot;
+    use hyper::header::Headers;
+    use hyper::mime::{Mime, SubLevel, TopLevel};
+    use hyper::status::StatusCode;
+
+    use super::{AuctionDataFileId, AuctionDataPointer, AuctionListing, Backoff, BattleNetApiClient, BattleNetApiError, BattleNetError,
+                ContentDecoder, CutoffMode, FailureLog, HttpResponse, IconSize, ItemInfo,
+                JsonContentDecoder, RealmInfo, RealmPopulation, RealmStatus, Region, RetryConfig, RetryDecision,
+                RetryPolicy, Transport, decode_item_info,
+                decode_json, is_stale_at, parse_auction_data_url, parse_auction_file, realms_from_status,
+                strip_owners};
+
+    /// Serves canned responses. `respond` gets the url and how many times it was
+    /// requested before. `requests` counts requests per url.
+    struct MockTransport<F: Fn(&str, usize) -> (StatusCode, String) + Send + Sync> {
+        respond: F,
+        requests: Arc<Mutex<HashMap<String, usize>>>,
+    }
+
+    impl<F: Fn(&str, usize) -> (StatusCode, String) + Send + Sync> MockTransport<F> {
+        fn new(respond: F) -> MockTransport<F> {
+            MockTransport { respond: respond, requests: Arc::new(Mutex::new(HashMap::new())) }
+        }
+    }
+
+    /// How many times urls containing `fragment` were requested.
+    fn request_count(requests: &Mutex<HashMap<String, usize>>, fragment: &str) -> usize {
+        requests.lock().unwrap().iter().filter(|&(url, _)| url.contains(fragment)).map(|(_, &count)| count).sum()
+    }
+
+    impl<F: Fn(&str, usize) -> (StatusCode, String) + Send + Sync> Transport for MockTransport<F> {
+        fn get(&self, url: &str, _: Headers) -> Result<HttpResponse, BattleNetApiError> {
+            let previous = {
+                let mut requests = self.requests.lock().unwrap();
+                let count = requests.entry(url.to_owned()).or_insert(0);
+                *count += 1;
+                *count - 1
+            };
+            let (status, body) = (self.respond)(url, previous);
+            Ok(HttpResponse { status: status, headers: Headers::new(), body: body.into_bytes() })
+        }
+    }
+
+    /// A client that sends everything to `transport` and never retries on its own.
+    fn mock_client<T: Transport + 'static>(transport: T) -> BattleNetApiClient {
+        BattleNetApiClient::builder("token")
+            .transport(Box::new(transport))
+            .retry_config(RetryConfig { max_retries: 0, ..Default::default() })
+            .build()
+    }
+
+    fn item_json(id: u64) -> String {
+        format!("{{\"id\":{},\"name\":\"Item {}\",\"icon\":\"inv_{}\"}}", id, id, id)
+    }
+
+    #[test]
+    fn test_failure_log_coalesces_repeats() {
+        let mut log = FailureLog::new(3);
+        assert!(log.failure("x", "boom", 1).is_some());
+        assert!(log.failure("x", "boom", 2).is_none());
+        assert!(log.failure("x", "boom", 3).is_none());
+        let summary = log.failure("x", "boom", 4).unwrap();
+        assert!(summary.contains("suppressed 3 identical errors"));
+        let changed = log.failure("x", "bang", 5).unwrap();
+        assert!(changed.contains("bang"));
+        assert!(changed.contains("Suppressed 3"));
+        assert!(log.success("x", 6).is_some());
+    }
+
+    #[test]
+    fn test_failure_log_quiet_on_first_try_success() {
+        let log = FailureLog::new(3);
+        assert!(log.success("x", 1).is_none());
+    }
+
+    #[test]
+    fn test_parse_auction_data_url() {
+        assert_eq!(
+            parse_auction_data_url("http://auction-api-us.worldofwarcraft.com/auction-data/AB1239c3bc437d48321a64e9e3932db4/auctions.json"),
+            Some(AuctionDataFileId::Hash("ab1239c3bc437d48321a64e9e3932db4".to_owned())));
+        assert_eq!(
+            parse_auction_data_url("http://us.battle.net/auction-data/earthen-ring/auctions.json"),
+            Some(AuctionDataFileId::Slug("earthen-ring".to_owned())));
+        assert_eq!(
+            parse_auction_data_url("http://auction-api-us.worldofwarcraft.com/auction-data/1136/auctions.json"),
+            Some(AuctionDataFileId::ConnectedRealm(1136)));
+        assert_eq!(parse_auction_data_url("http://example.com/something/else.json"), None);
+    }
+
+    #[test]
+    fn test_icon_url_uses_region_render_host() {
+        let item = ItemInfo { id: 124124, name: "Blood of Sargeras".to_owned(), icon: "inv_blood_of_sargeras".to_owned(),
+                              purchase_price: None, item_class: None, item_subclass: None };
+        let eu = BattleNetApiClient::builder("token").region(Region::Eu).build();
+        assert_eq!(eu.icon_url(&item, IconSize::Large),
+                   "https://render-eu.worldofwarcraft.com/icons/56/inv_blood_of_sargeras.jpg");
+        let cn = BattleNetApiClient::builder("token").region(Region::Cn).build();
+        assert_eq!(cn.icon_url(&item, IconSize::Small),
+                   "https://render.worldofwarcraft.com.cn/icons/18/inv_blood_of_sargeras.jpg");
+        let cdn = BattleNetApiClient::builder("token").render_host("cdn.example.com").build();
+        assert_eq!(cdn.icon_url(&item, IconSize::Medium),
+                   "https://cdn.example.com/icons/36/inv_blood_of_sargeras.jpg");
+    }
+
+    #[test]
+    fn test_unit_price_legacy_stack() {
+        let listing = AuctionListing { item: 1, buyout: 2000, quantity: 20, ..Default::default() };
+        assert_eq!(listing.unit_price(), 100);
+        assert_eq!(listing.total_buyout(), 2000);
+    }
+
+    #[test]
+    fn test_unit_price_commodity() {
+        let listing = AuctionListing { item: 1, quantity: 20, unit_price: Some(100), ..Default::default() };
+        assert_eq!(listing.unit_price(), 100);
+        assert_eq!(listing.total_buyout(), 2000);
+    }
+
+    #[test]
+    fn test_backoff_grows_to_max() {
+        let config = RetryConfig { initial_backoff: Duration::from_millis(100), max_backoff: Duration::from_millis(350), ..Default::default() };
+        let mut backoff = Backoff::new(&config);
+        assert_eq!(backoff.next_delay(), Duration::from_millis(100));
+        assert_eq!(backoff.next_delay(), Duration::from_millis(200));
+        assert_eq!(backoff.next_delay(), Duration::from_millis(350));
+        assert_eq!(backoff.next_delay(), Duration::from_millis(350));
+    }
+
+    #[test]
+    fn test_backoff_starts_from_base_for_each_call() {
+        // make_blizzard_api_call creates a Backoff per call, so a failure after an
+        // earlier call's retries sees the base delay again.
+        let config = RetryConfig { initial_backoff: Duration::from_millis(100), max_backoff: Duration::from_secs(1), ..Default::default() };
+        let mut first_call = Backoff::new(&config);
+        first_call.next_delay();
+        first_call.next_delay();
+        let mut second_call = Backoff::new(&config);
+        assert_eq!(second_call.next_delay(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_zero_retries_is_single_shot() {
+        let client = BattleNetApiClient::builder("token")
+            .retry_config(RetryConfig { max_retries: 0, initial_backoff: Duration::from_secs(60), ..Default::default() })
+            .build();
+        let start_time = Instant::now();
+        // Nothing listens on port 1, so the connection is refused straight away.
+        match client.make_blizzard_api_call::<Vec<RealmInfo>>("http://127.0.0.1:1/", "nothing") {
+            Err(BattleNetApiError::NetworkError(_)) => (),
+            other => panic!("Expected a network error, got {:?}", other),
+        }
+        assert!(Instant::now() - start_time < Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_strip_owners() {
+        assert_eq!(strip_owners("{\"owner\":\"Sqrl\",\"item\":1}"), "{\"owner\":\"_\",\"item\":1}");
+    }
+
+    #[test]
+    fn test_decode_battle_net_error() {
+        let error: BattleNetError =
+            decode_json("{\"code\":404,\"type\":\"BLZWEBAPI00000404\",\"detail\":\"Not Found\"}").unwrap();
+        assert_eq!(error, BattleNetError {
+            code: 404,
+            error_type: "BLZWEBAPI00000404".to_owned(),
+            detail: "Not Found".to_owned(),
+        });
+    }
+
+    #[test]
+    fn test_process_connected_realms_empty_is_singleton() {
+        let realm = |slug: &str, connected: &[&str]| RealmInfo {
+            name: slug.to_owned(),
+            slug: slug.to_owned(),
+            connected_realms: connected.iter().map(|s| s.to_string()).collect(),
+            population: None,
+        };
+        let realms = vec![
+            realm("aegwynn", &["aegwynn", "bonechewer"]),
+            realm("bonechewer", &["aegwynn", "bonechewer"]),
+            realm("zuljin", &[]),
+        ];
+        assert_eq!(BattleNetApiClient::process_connected_realms(&realms), vec![
+            vec!["aegwynn".to_owned(), "bonechewer".to_owned()],
+            vec!["zuljin".to_owned()],
+        ]);
+    }
+
+    #[test]
+    fn test_process_connected_realms_dedups_slugs() {
+        let realm = |slug: &str, connected: &[&str]| RealmInfo {
+            name: slug.to_owned(),
+            slug: slug.to_owned(),
+            connected_realms: connected.iter().map(|s| s.to_string()).collect(),
+            population: None,
+        };
+        let realms = vec![
+            realm("aegwynn", &["aegwynn", "bonechewer", "aegwynn"]),
+            realm("bonechewer", &["aegwynn", "bonechewer"]),
+        ];
+        assert_eq!(BattleNetApiClient::process_connected_realms(&realms), vec![
+            vec!["aegwynn".to_owned(), "bonechewer".to_owned()],
+        ]);
+    }
+
+    #[test]
+    fn test_parse_auction_file() {
+        let config = ArchiveConfig {
+            dir: env::temp_dir().join("blood-money-test-archive"),
+            naming: NamingScheme::SlugTimestamp,
+            gzip: true,
+        };
+        let body = "{\"realms\":[],\"auctions\":[{\"auc\":1,\"item\":124124,\"owner\":\"Bl\\u00e4h\",                    \"buyout\":500,\"quantity\":5}]}";
+        let path = config.write_dump("earthen-ring", 1480000000000, body.as_bytes()).unwrap();
+        let (last_modified, listings) = parse_auction_file(&path).unwrap();
+        fs::remove_dir_all(&config.dir).unwrap();
+        assert_eq!(last_modified, 1480000000000);
+        assert_eq!(listings.len(), 1);
+        assert_eq!((listings[0].item, listings[0].buyout, listings[0].quantity), (124124, 500, 5));
+        match parse_auction_file(&config.dir.join("missing.json")) {
+            Err(BattleNetApiError::ReadError(_)) => (),
+            other => panic!("Expected a read error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_unknown_realm_population() {
+        let realms = realms_from_status(decode_json("{\"realms\":[\
+            {\"name\":\"A\",\"slug\":\"a\",\"connected_realms\":[\"a\"],\"population\":\"medium\"},\
+            {\"name\":\"B\",\"slug\":\"b\",\"connected_realms\":[\"b\"],\"population\":\"overflowing\"},\
+            {\"name\":\"C\",\"slug\":\"c\",\"connected_realms\":[\"c\"]}]}").unwrap()).unwrap();
+        assert_eq!(realms[0].population, Some(RealmPopulation::Medium));
+        assert_eq!(realms[1].population, Some(RealmPopulation::Unknown("overflowing".to_owned())));
+        assert_eq!(realms[2].population, None);
+        assert_eq!(RealmStatus::from_type("UP"), RealmStatus::Up);
+        assert_eq!(RealmStatus::from_type("MAINTENANCE"), RealmStatus::Unknown("MAINTENANCE".to_owned()));
+    }
+
+    #[test]
+    fn test_decode_item_info_plain_name() {
+        let item = decode_item_info("{\"id\":1,\"name\":\"Ore\",\"icon\":\"inv_ore\"}", "de_DE").unwrap();
+        assert_eq!((item.id, &item.name[..], &item.icon[..], item.purchase_price), (1, "Ore", "inv_ore", None));
+    }
+
+    #[test]
+    fn test_decode_item_info_purchase_price() {
+        let item = decode_item_info("{\"id\":1,\"name\":\"Thread\",\"icon\":\"inv_thread\",\"purchasePrice\":100}",
+                                    "en_US").unwrap();
+        assert_eq!(item.purchase_price, Some(100));
+        let item = decode_item_info("{\"id\":1,\"name\":\"Ore\",\"icon\":\"inv_ore\",\"itemClass\":7,\"itemSubClass\":7}",
+                                    "en_US").unwrap();
+        assert_eq!((item.item_class, item.item_subclass), (Some(7), Some(7)));
+    }
+
+    #[test]
+    fn test_decode_item_info_localized_name() {
+        let body = "{\"id\":1,\"name\":{\"en_US\":\"Ore\",\"de_DE\":\"Erz\"},\"icon\":\"inv_ore\"}";
+        assert_eq!(decode_item_info(body, "de_DE").unwrap().name, "Erz");
+        assert_eq!(decode_item_info(body, "fr_FR").unwrap().name, "Ore");
+        let body = "{\"id\":1,\"name\":{\"ko_KR\":\"광석\"},\"icon\":\"inv_ore\"}";
+        assert_eq!(decode_item_info(body, "en_US").unwrap().name, "광석");
+    }
+
+    #[test]
+    fn test_empty_realm_list_is_ok() {
+        let realms = realms_from_status(decode_json("{\"realms\":[]}").unwrap()).unwrap();
+        assert!(realms.is_empty());
+    }
+
+    #[test]
+    fn test_missing_realm_list_is_malformed() {
+        match realms_from_status(decode_json("{}").unwrap()) {
+            Err(BattleNetApiError::MalformedResponse(_)) => (),
+            other => panic!("Expected a malformed response error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_json_content_decoder() {
+        let json_type = Mime(TopLevel::Application, SubLevel::Json, vec![]);
+        let html_type = Mime(TopLevel::Text, SubLevel::Html, vec![]);
+        assert!(JsonContentDecoder.handles(&json_type));
+        assert!(!JsonContentDecoder.handles(&html_type));
+        assert_eq!(JsonContentDecoder.decode_content(b"{\"realms\":[]}".to_vec()).unwrap(), "{\"realms\":[]}");
+        match JsonContentDecoder.decode_content(vec![0xff, 0xfe]) {
+            Err(BattleNetApiError::ReadError(_)) => (),
+            other => panic!("expected ReadError, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_html_content_type_is_upstream_html() {
+        use hyper::header::ContentType;
+
+        /// Serves a Latin-1 error page, which isn't valid UTF-8.
+        struct HtmlTransport;
+
+        impl Transport for HtmlTransport {
+            fn get(&self, _: &str, _: Headers) -> Result<HttpResponse, BattleNetApiError> {
+                let mut headers = Headers::new();
+                headers.set(ContentType(Mime(TopLevel::Text, SubLevel::Html, vec![])));
+                Ok(HttpResponse { status: StatusCode::Ok, headers: headers, body: b"<html>Wartung \xe4</html>".to_vec() })
+            }
+        }
+
+        match mock_client(HtmlTransport).get_realms() {
+            Err(BattleNetApiError::UpstreamHtml) => (),
+            other => panic!("Expected UpstreamHtml, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_get_item_infos_only_retries_failures() {
+        let transport = MockTransport::new(|url: &str, previous| {
+            if url.contains("/item/2?") && previous == 0 {
+                return (StatusCode::InternalServerError, String::new());
+            }
+            let id = if url.contains("/item/1?") { 1 } else if url.contains("/item/2?") { 2 } else { 3 };
+            (StatusCode::Ok, item_json(id))
+        });
+        let requests = transport.requests.clone();
+        let client = mock_client(transport);
+        let results = client.get_item_infos(&[1, 2, 3, 1], 2, 1);
+        assert_eq!(results.len(), 3);
+        assert!(results.values().all(|result| result.is_ok()));
+        assert_eq!(results[&2].as_ref().unwrap().name, "Item 2");
+        assert_eq!(request_count(&requests, "/item/1?"), 1);
+        assert_eq!(request_count(&requests, "/item/2?"), 2);
+        assert_eq!(request_count(&requests, "/item/3?"), 1);
+    }
+
+    #[test]
+    fn test_realm_cache_expires_after_ttl() {
+        let transport = MockTransport::new(|_: &str, _| {
+            (StatusCode::Ok, "{\"realms\":[{\"name\":\"Earthen Ring\",\"slug\":\"earthen-ring\",\
+                              \"connected_realms\":[\"earthen-ring\"]}]}".to_owned())
+        });
+        let requests = transport.requests.clone();
+        let clock = Arc::new(ManualClock::new());
+        let client = BattleNetApiClient::builder("token")
+            .transport(Box::new(transport))
+            .clock(clock.clone())
+            .realm_cache_ttl(Duration::from_secs(60))
+            .build();
+        client.get_realms().unwrap();
+        clock.advance(Duration::from_secs(59));
+        client.get_realms().unwrap();
+        assert_eq!(request_count(&requests, "realm/status"), 1);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(client.get_realms().unwrap()[0].slug, "earthen-ring");
+        assert_eq!(request_count(&requests, "realm/status"), 2);
+    }
+
+    #[test]
+    fn test_backoff_sleeps_on_clock() {
+        let transport = MockTransport::new(|_: &str, _| (StatusCode::InternalServerError, String::new()));
+        let clock = Arc::new(ManualClock::new());
+        let client = BattleNetApiClient::builder("token")
+            .transport(Box::new(transport))
+            .clock(clock.clone())
+            .retry_config(RetryConfig {
+                max_retries: 3,
+                initial_backoff: Duration::from_secs(10),
+                max_backoff: Duration::from_secs(20),
+            })
+            .build();
+        assert!(client.get_item_info(1).is_err());
+        assert_eq!(clock.elapsed(), Duration::from_secs(10 + 20 + 20));
+    }
+
+    #[test]
+    fn test_resolve_names() {
+        let transport = MockTransport::new(|url: &str, _| {
+            if url.contains("/item/1?") {
+                (StatusCode::Ok, item_json(1))
+            } else {
+                (StatusCode::InternalServerError, String::new())
+            }
+        });
+        let client = mock_client(transport);
+        let listings = vec![
+            AuctionListing { item: 1, buyout: 10, quantity: 1, ..Default::default() },
+            AuctionListing { item: 2, buyout: 10, quantity: 1, ..Default::default() },
+            AuctionListing { item: 1, buyout: 20, quantity: 1, ..Default::default() },
+        ];
+        let names: Vec<String> = client.resolve_names(&listings).into_iter().map(|(_, name)| name).collect();
+        assert_eq!(names, vec!["Item 1", "item:2", "Item 1"]);
+    }
+
+    #[test]
+    fn test_search_all_items_pages() {
+        let transport = MockTransport::new(|url: &str, _| {
+            let page = if url.contains("_page=1&") { 1 } else { 2 };
+            (StatusCode::Ok, format!("{{\"page\":{},\"pageSize\":1,\"pageCount\":2,\"resultCountCapped\":false,\
+                                      \"results\":[{{\"data\":{{\"id\":{},\"name\":{{\"en_US\":\"Blood {}\"}}}}}}]}}",
+                                     page, page, page))
+        });
+        let requests = transport.requests.clone();
+        let client = mock_client(transport);
+        let first = client.search_items("blood", 1, 1).unwrap();
+        assert_eq!((first.page, first.page_count, first.results_capped), (1, 2, false));
+        let results = client.search_all_items("blood", 1).unwrap();
+        assert_eq!(results.iter().map(|r| r.id).collect::<Vec<u64>>(), vec![1, 2]);
+        assert_eq!(results[1].name, "Blood 2");
+        assert_eq!(request_count(&requests, "search/item"), 3);
+    }
+
+    #[test]
+    fn test_is_stale() {
+        let now = UNIX_EPOCH + Duration::from_secs(1480000000);
+        let hour = Duration::from_secs(60 * 60);
+        assert!(!is_stale_at(1480000000000 - 30 * 60 * 1000, hour, now));
+        assert!(is_stale_at(1480000000000 - 2 * 60 * 60 * 1000, hour, now));
+        assert!(!is_stale_at(1480000000000 + 1000, hour, now));
+
+        let clock = Arc::new(ManualClock::starting_at(now));
+        let client = BattleNetApiClient::builder("token").clock(clock.clone()).build();
+        assert!(!client.is_stale(1480000000000, hour));
+        clock.advance(hour * 2);
+        assert!(client.is_stale(1480000000000, hour));
+    }
+
+    #[test]
+    fn test_get_profession_recipes() {
+        let transport = MockTransport::new(|url: &str, _| {
+            let body = if url.contains("/skill-tier/2485?") {
+                "{\"categories\":[{\"name\":\"Potions\",\"recipes\":[{\"id\":1,\"name\":\"A\"}]},\
+                  {\"name\":\"Flasks\",\"recipes\":[{\"id\":2,\"name\":\"B\"}]}]}"
+            } else if url.contains("/skill-tier/2486?") {
+                "{\"id\":2486}"
+            } else {
+                "{\"id\":171,\"name\":\"Alchemy\",\"skill_tiers\":[{\"id\":2485,\"name\":\"Legion\"},\
+                  {\"id\":2486,\"name\":\"Classic\"}]}"
+            };
+            (StatusCode::Ok, body.to_owned())
+        });
+        let client = mock_client(transport);
+        let recipes = client.get_profession_recipes(171).unwrap();
+        assert_eq!(recipes[&2485].iter().map(|r| r.id).collect::<Vec<u64>>(), vec![1, 2]);
+        assert!(recipes[&2486].is_empty());
+    }
+
+    #[test]
+    fn test_field_filtering() {
+        // The mock ignores the fields parameter, like an endpoint without support for it.
+        let transport = MockTransport::new(|_: &str, _| {
+            (StatusCode::Ok, "{\"id\":5,\"name\":\"Ore\",\"icon\":\"inv_ore\",\"quality\":1,\"itemLevel\":10}".to_owned())
+        });
+        let requests = transport.requests.clone();
+        let client = BattleNetApiClient::builder("token")
+            .transport(Box::new(transport))
+            .field_filtering(true)
+            .build();
+        assert_eq!(client.get_item_info(5).unwrap().name, "Ore");
+        assert_eq!(request_count(&requests, "item/5?locale=en_US&apikey=token&fields=id,name,icon"), 1);
+
+        let transport = MockTransport::new(|_: &str, _| (StatusCode::Ok, item_json(5)));
+        let requests = transport.requests.clone();
+        mock_client(transport).get_item_info(5).unwrap();
+        assert_eq!(request_count(&requests, "fields="), 0);
+    }
+
+    #[test]
+    fn test_throttle_wait_counts_against_deadline() {
+        let transport = MockTransport::new(|url: &str, _| {
+            let id: u64 = url.split("/item/").nth(1).unwrap().split('?').next().unwrap().parse().unwrap();
+            (StatusCode::Ok, item_json(id))
+        });
+        let requests = transport.requests.clone();
+        let clock = Arc::new(ManualClock::new());
+        let client = BattleNetApiClient::builder("token")
+            .transport(Box::new(transport))
+            .clock(clock.clone())
+            .build();
+        // The throttle lets 100 requests through a second, so these use it up.
+        for id in 0..100 {
+            client.get_item_info(id).unwrap();
+        }
+        assert_eq!(clock.elapsed(), Duration::new(0, 0));
+        let deadline = clock.now() + Duration::from_millis(500);
+        match client.get_auction_listings_deadline("earthen-ring", 0, deadline) {
+            Err(BattleNetApiError::TimedOut) => (),
+            other => panic!("Expected a timeout, got {:?}", other),
+        }
+        // The wait for the throttle moved the clock past the deadline, so the pointer
+        // request was never sent.
+        assert_eq!(clock.elapsed(), Duration::from_secs(1));
+        assert_eq!(request_count(&requests, "auction/data/"), 0);
+    }
+
+    #[test]
+    fn test_get_auction_listings_deadline() {
+        let transport = MockTransport::new(|url: &str, _| {
+            if url.contains("auction/data/") {
+                (StatusCode::Ok, "{\"files\":[{\"url\":\"https://data/auctions.json\",\"lastModified\":10}]}".to_owned())
+            } else {
+                (StatusCode::ServiceUnavailable, String::new())
+            }
+        });
+        let requests = transport.requests.clone();
+        let clock = Arc::new(ManualClock::new());
+        let client = BattleNetApiClient::builder("token")
+            .transport(Box::new(transport))
+            .clock(clock.clone())
+            .retry_config(RetryConfig {
+                max_retries: 100,
+                initial_backoff: Duration::from_secs(10),
+                max_backoff: Duration::from_secs(60),
+            })
+            .build();
+        // Backoffs of 10s and 20s fit in the budget but the next 40s wouldn't.
+        let deadline = clock.now() + Duration::from_secs(60);
+        match client.get_auction_listings_deadline("earthen-ring", 0, deadline) {
+            Err(BattleNetApiError::TimedOut) => (),
+            other => panic!("Expected a timeout, got {:?}", other),
+        }
+        assert_eq!(request_count(&requests, "https://data/auctions.json"), 3);
+        assert_eq!(clock.elapsed(), Duration::from_secs(30));
+
+        match client.get_auction_listings_deadline("earthen-ring", 0, clock.now()) {
+            Err(BattleNetApiError::TimedOut) => (),
+            other => panic!("Expected a timeout, got {:?}", other),
+        }
+        assert_eq!(request_count(&requests, "auction/data/"), 1);
+    }
+
+    #[test]
+    fn test_metrics() {
+        let transport = MockTransport::new(|_: &str, previous| match previous {
+            0 => (StatusCode::Ok, "not json".to_owned()),
+            _ => (StatusCode::Ok, item_json(1)),
+        });
+        let clock = Arc::new(ManualClock::starting_at(UNIX_EPOCH + Duration::from_secs(1000)));
+        let client = BattleNetApiClient::builder("token")
+            .transport(Box::new(transport))
+            .clock(clock.clone())
+            .retry_config(RetryConfig { max_retries: 1, initial_backoff: Duration::from_secs(5), ..Default::default() })
+            .build();
+        assert_eq!(client.metrics(), MetricsSnapshot::default());
+        client.get_item_info(1).unwrap();
+        assert_eq!(client.metrics(), MetricsSnapshot {
+            requests: 2,
+            retries: 1,
+            bytes_downloaded: (8 + item_json(1).len()) as u64,
+            decode_errors: 1,
+            last_successful_fetch: Some(1005),
+        });
+    }
+
+    #[test]
+    fn test_max_requests() {
+        let transport = MockTransport::new(|url: &str, _| {
+            let id: u64 = url.split("/item/").nth(1).unwrap().split('?').next().unwrap().parse().unwrap();
+            (StatusCode::Ok, item_json(id))
+        });
+        let requests = transport.requests.clone();
+        let client = BattleNetApiClient::builder("token")
+            .transport(Box::new(transport))
+            .max_requests(2)
+            .build();
+        assert_eq!(client.remaining_requests(), Some(2));
+        client.get_item_info(1).unwrap();
+        client.get_item_info(2).unwrap();
+        // Cached, so it doesn't need a request.
+        client.get_item_info(1).unwrap();
+        assert_eq!(client.remaining_requests(), Some(0));
+        match client.get_item_info(3) {
+            Err(BattleNetApiError::QuotaExhausted) => (),
+            other => panic!("Expected the quota to be exhausted, got {:?}", other),
+        }
+        assert_eq!(request_count(&requests, "/item/"), 2);
+        assert_eq!(mock_client(MockTransport::new(|_: &str, _| (StatusCode::Ok, item_json(1)))).remaining_requests(), None);
+    }
+
+    #[test]
+    fn test_stream_auction_data_to() {
+        let dump = "{\"auctions\":[{\"item\":1,\"owner\":\"Kept\",\"buyout\":5,\"quantity\":1}]}";
+        let transport = MockTransport::new(move |url: &str, _| {
+            if url.contains("auction/data/") {
+                (StatusCode::Ok, "{\"files\":[{\"url\":\"https://data/auctions.json\",\"lastModified\":42}]}".to_owned())
+            } else {
+                (StatusCode::Ok, dump.to_owned())
+            }
+        });
+        let client = mock_client(transport);
+        let mut out = Vec::new();
+        assert_eq!(client.stream_auction_data_to("earthen-ring", &mut out).unwrap(), 42);
+        assert_eq!(String::from_utf8(out).unwrap(), dump);
+        assert_eq!(client.metrics().bytes_downloaded as usize, dump.len() + 66);
+    }
+
+    #[test]
+    fn test_auction_data_size_counts_requests() {
+        let transport = MockTransport::new(|url: &str, _| {
+            if url.contains("auction/data/") {
+                (StatusCode::Ok, "{\"files\":[{\"url\":\"https://data/auctions.json\",\"lastModified\":42}]}".to_owned())
+            } else {
+                (StatusCode::Ok, String::new())
+            }
+        });
+        let requests = transport.requests.clone();
+        let client = BattleNetApiClient::builder("token")
+            .transport(Box::new(transport))
+            .max_requests(1)
+            .build();
+        match client.get_auction_data_size("earthen-ring") {
+            Err(BattleNetApiError::QuotaExhausted) => (),
+            other => panic!("Expected the quota to be exhausted, got {:?}", other),
+        }
+        assert_eq!(request_count(&requests, "auctions.json"), 0);
+        assert_eq!(client.metrics().requests, 1);
+    }
+
+    #[test]
+    fn test_before_request_sees_downloads() {
+        let transport = MockTransport::new(|url: &str, _| {
+            if url.contains("auction/data/") {
+                (StatusCode::Ok, "{\"files\":[{\"url\":\"https://data/auctions.json\",\"lastModified\":42}]}".to_owned())
+            } else {
+                (StatusCode::Ok, "{\"auctions\":[]}".to_owned())
+            }
+        });
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let hook_seen = seen.clone();
+        let client = BattleNetApiClient::builder("token")
+            .transport(Box::new(transport))
+            .before_request(Box::new(move |url: &str, _: &mut Headers| hook_seen.lock().unwrap().push(url.to_owned())))
+            .build();
+        client.stream_auction_data_to("earthen-ring", Vec::new()).unwrap();
+        client.get_auction_data_size("earthen-ring").unwrap();
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 4);
+        assert_eq!(seen.iter().filter(|url| url.as_str() == "https://data/auctions.json").count(), 2);
+    }
+
+    #[test]
+    fn test_cutoff_mode() {
+        fn serve(url: &str, _: usize) -> (StatusCode, String) {
+            if url.contains("auction/data/") {
+                (StatusCode::Ok, "{\"files\":[{\"url\":\"https://data/auctions.json\",\"lastModified\":10}]}".to_owned())
+            } else {
+                (StatusCode::Ok, "{\"auctions\":[{\"item\":1,\"buyout\":5,\"quantity\":1}]}".to_owned())
+            }
+        }
+        assert!(mock_client(MockTransport::new(serve)).get_auction_listings("a", 10).unwrap().is_none());
+        let client = BattleNetApiClient::builder("token")
+            .transport(Box::new(MockTransport::new(serve)))
+            .cutoff_mode(CutoffMode::Inclusive)
+            .build();
+        assert_eq!(client.get_auction_listings("a", 10).unwrap().unwrap().0, 10);
+        assert!(client.get_auction_listings("a", 11).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_resolve_and_download_auction_data() {
+        let client = mock_client(MockTransport::new(|url: &str, _| {
+            if url.contains("auction/data/") {
+                (StatusCode::Ok, "{\"files\":[{\"url\":\"https://data/1.json\",\"lastModified\":10},\
+                                  {\"url\":\"https://data/2.json\",\"lastModified\":20}]}".to_owned())
+            } else if url.contains("data/1.json") {
+                (StatusCode::Ok, "{\"auctions\":[{\"item\":1,\"buyout\":5,\"quantity\":1}]}".to_owned())
+            } else {
+                (StatusCode::Ok, "{\"auctions\":[{\"item\":2,\"buyout\":5,\"quantity\":1}]}".to_owned())
+            }
+        }));
+        let pointer = client.resolve_auction_pointer("a").unwrap().unwrap();
+        assert_eq!(pointer, AuctionDataPointer {
+            realm_slug: "a".to_owned(),
+            last_modified: 20,
+            files: vec![(10, "https://data/1.json".to_owned()), (20, "https://data/2.json".to_owned())],
+        });
+        let items: Vec<u64> = client.download_auction_data(&pointer).unwrap().iter().map(|listing| listing.item).collect();
+        assert_eq!(items, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        /// Retries only 503s.
+        struct RetryUnavailable;
+
+        impl RetryPolicy for RetryUnavailable {
+            fn should_retry(&self, status: Option<StatusCode>, _: u32, _: &BattleNetApiError) -> RetryDecision {
+                if status == Some(StatusCode::ServiceUnavailable) { RetryDecision::Retry } else { RetryDecision::GiveUp }
+            }
+        }
+
+        let transport = MockTransport::new(|_: &str, previous| {
+            if previous < 2 { (StatusCode::ServiceUnavailable, String::new()) } else { (StatusCode::InternalServerError, String::new()) }
+        });
+        let requests = transport.requests.clone();
+        let client = BattleNetApiClient::builder("token")
+            .transport(Box::new(transport))
+            .clock(Arc::new(ManualClock::new()))
+            .retry_policy(Box::new(RetryUnavailable))
+            .build();
+        match client.get_item_info(1) {
+            Err(BattleNetApiError::HttpStatus(StatusCode::InternalServerError, _)) => (),
+            other => panic!("Expected the 500 to be returned, got {:?}", other),
+        }
+        assert_eq!(request_count(&requests, "item/1"), 3);
+    }
+
+    #[test]
+    fn test_download_auction_data_from_url() {
+        let transport = MockTransport::new(|_: &str, _| {
+            (StatusCode::Ok, "{\"auctions\":[{\"item\":1,\"owner\":\"Someone\",\"buyout\":5,\"quantity\":1}]}".to_owned())
+        });
+        let requests = transport.requests.clone();
+        let client = mock_client(transport);
+        let listings = client.download_auction_data_from_url("https://data/old.json").unwrap();
+        assert_eq!(listings.iter().map(|listing| listing.item).collect::<Vec<u64>>(), vec![1]);
+        assert_eq!(request_count(&requests, "auction/data/"), 0);
+        assert_eq!(request_count(&requests, "data/old.json"), 1);
+    }
+
+    #[test]
+    fn test_removed_item_is_not_found() {
+        let transport = MockTransport::new(|url: &str, _| {
+            if url.contains("/item/2?") {
+                (StatusCode::NotFound, "{\"status\":\"nok\",\"reason\":\"unable to get item information.\"}".to_owned())
+            } else if url.contains("auction/data/") {
+                (StatusCode::NotFound, String::new())
+            } else {
+                (StatusCode::Ok, item_json(1))
+            }
+        });
+        let requests = transport.requests.clone();
+        let client = BattleNetApiClient::builder("token")
+            .transport(Box::new(transport))
+            .clock(Arc::new(ManualClock::new()))
+            .retry_config(RetryConfig { max_retries: 1, ..Default::default() })
+            .build();
+        match client.get_item_info(2) {
+            Err(BattleNetApiError::ItemNotFound(2)) => (),
+            other => panic!("Expected ItemNotFound, got {:?}", other),
+        }
+        assert_eq!(request_count(&requests, "/item/2?"), 1);
+        let results = client.get_item_infos(&[1, 2], 2, 3);
+        assert_eq!(results[&1].as_ref().unwrap().name, "Item 1");
+        assert_eq!(results[&2].as_ref().unwrap().name, "item:2");
+        assert_eq!(request_count(&requests, "/item/2?"), 2);
+        // Other endpoints still retry 404s.
+        assert!(client.get_auction_data_url("earthen-ring").is_err());
+        assert_eq!(request_count(&requests, "auction/data/"), 2);
     }
 }
@@ -1,7 +1,18 @@
+#![feature(proc_macro)]
+
 extern crate hyper;
+extern crate regex;
+#[cfg(feature = "rustc-serialize")]
 extern crate rustc_serialize;
 extern crate scoped_threadpool;
-
+#[cfg(not(feature = "rustc-serialize"))]
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+
+use regex::Regex;
+#[cfg(feature = "rustc-serialize")]
 use rustc_serialize::json;
 use std::collections::{BTreeMap, HashMap};
 use std::env;
@@ -10,7 +21,8 @@ use std::io::Read;
 use hyper::client::{Client, Response};
 
 /// All we care about for every realm is its "slug".
-#[derive(Debug, RustcDecodable)]
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "rustc-serialize", derive(RustcDecodable))]
 struct RealmInfo {
     name: String,
     slug: String,
@@ -25,20 +37,23 @@ struct RealmAuctionInfo {
 }
 
 /// Represents the JSON reply from the auction data status endpoint.
-#[derive(Debug, RustcDecodable)]
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "rustc-serialize", derive(RustcDecodable))]
 #[allow(non_snake_case)]
 struct AuctionDataPointer {
     url: String,
     lastModified: u64,
 }
 
-#[derive(Debug, RustcDecodable)]
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "rustc-serialize", derive(RustcDecodable))]
 struct AuctionDataReply {
     files: Vec<AuctionDataPointer>, // Will always be 1 element.
 }
 
 /// The fields we care about in blizzard's auction reply.
-#[derive(Debug, RustcDecodable)]
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "rustc-serialize", derive(RustcDecodable))]
 struct AuctionListing {
     item: u64,
     buyout: u64,
@@ -46,20 +61,41 @@ struct AuctionListing {
 }
 
 /// Represents the reply from blizzard's auction data urls.
-#[derive(Debug, RustcDecodable)]
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "rustc-serialize", derive(RustcDecodable))]
 struct AuctionListingsData {
     realms: Vec<RealmInfo>,
     auctions: Vec<AuctionListing>,
 }
 
 /// Represents a single item for sale from the blood vendor.
-#[derive(Debug, RustcDecodable)]
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "rustc-serialize", derive(RustcDecodable))]
 struct BloodVendorItem {
     name: String,
     quantity: u64,
     id: u64,
 }
 
+/// Decodes with rustc_serialize when the `rustc-serialize` feature is enabled
+/// (the default), and serde otherwise, like the library does.
+#[cfg(feature = "rustc-serialize")]
+fn decode<T: rustc_serialize::Decodable>(body: &str) -> T {
+    json::decode(body).expect("Malformed json reply.")
+}
+
+#[cfg(not(feature = "rustc-serialize"))]
+fn decode<T: serde::Deserialize>(body: &str) -> T {
+    serde_json::from_str(body).expect("Malformed json reply.")
+}
+
+/// Blanks every auction owner name. Blizzard often puts invalid unicode in them
+/// which breaks decoding, and we never look at them anyway.
+fn strip_owners(body: &str) -> String {
+    let owner = Regex::new("\"owner\":\"([^\"]+?)\"").unwrap();
+    owner.replace_all(body, "\"owner\":\"_\"")
+}
+
 // /// Given a vector of (quanity, buyout) tuples, returns the lowest buyout and the 10th percentile buyout.
 // /// TODO: Make percentile parameterized
 // fn calculate_lowest_buyout_and_10th_percentile(quantities_and_prices: &Vec<(u64, u64)>) -> (u64, u64) {
@@ -76,7 +112,7 @@ fn main() {
         }
     };
 
-    let items: Vec<BloodVendorItem> = json::decode(include_str!("../../catalog/items.json")).expect("Error reading items.");
+    let items: Vec<BloodVendorItem> = decode(include_str!("../../catalog/items.json"));
     let item_ids: HashMap<u64, BloodVendorItem> = items.into_iter().map(|x| (x.id, x)).collect();
 
     let client = Client::new();
@@ -108,7 +144,7 @@ fn main() {
         }
         succeeded = true;
     }
-    let mut auction_data_reply: AuctionDataReply = json::decode(&s).expect("Malformed json reply.");
+    let mut auction_data_reply: AuctionDataReply = decode(&s);
     let auction_data_pointer = auction_data_reply.files.pop().unwrap();
 
     // Download the auction data but don't do anything with it for now.
@@ -139,7 +175,7 @@ fn main() {
         }
         succeeded = true;
     }
-    let auction_listings_data: AuctionListingsData = json::decode(&s).unwrap();
+    let auction_listings_data: AuctionListingsData = decode(&strip_owners(&s));
 
     let mut realm_auction_info = RealmAuctionInfo {
         last_update: auction_data_pointer.lastModified,
@@ -0,0 +1,97 @@
+//! Where the client gets the current time from. Anything time based in the
+//! client (cache TTLs, backoff between retries, deadlines, throttle waits) goes
+//! through a Clock, so tests can swap in a `ManualClock` and move time along
+//! without actually sleeping.
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    /// The wall clock time, for comparing against timestamps from Blizzard.
+    fn system_time(&self) -> SystemTime;
+    /// Waits for `duration` to pass.
+    fn sleep(&self, duration: Duration);
+}
+
+/// The real clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// A clock that only moves when told to. Sleeping on it returns immediately
+/// after advancing it by the sleep's duration.
+pub struct ManualClock {
+    start: Instant,
+    start_system_time: SystemTime,
+    elapsed: Mutex<Duration>,
+}
+
+impl ManualClock {
+    /// A clock starting at the current time.
+    pub fn new() -> ManualClock {
+        ManualClock::starting_at(SystemTime::now())
+    }
+
+    /// A clock whose wall clock time starts at `time`.
+    pub fn starting_at(time: SystemTime) -> ManualClock {
+        ManualClock {
+            start: Instant::now(),
+            start_system_time: time,
+            elapsed: Mutex::new(Duration::new(0, 0)),
+        }
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+
+    /// How far the clock has been moved since it was created.
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.start_system_time + self.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        clock.advance(Duration::from_secs(5));
+        clock.sleep(Duration::from_secs(2));
+        assert_eq!(clock.now() - start, Duration::from_secs(7));
+        assert_eq!(clock.elapsed(), Duration::from_secs(7));
+    }
+}
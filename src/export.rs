@@ -0,0 +1,208 @@
+//! Exporting auction data for other programs to consume.
+//! Exports use their own record types rather than serializing the API structs
+//! directly, so that renaming a field in battle_net_api_client doesn't silently
+//! change what downstream consumers see.
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Serializer};
+use serde_json;
+use sha2::{Digest, Sha256};
+
+use battle_net_api_client::AuctionListing;
+
+/// Version of the exported listing schema. Bump this whenever a field of
+/// `ExportedListing` is added, removed, renamed or changes meaning.
+pub const LISTING_SCHEMA_VERSION: u32 = 1;
+
+/// Settings shared by the exporters.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExportOptions {
+    /// Write copper amounts as strings, e.g. `"buyout_copper":"500"`. Big buyouts
+    /// can exceed 2^53, past which JavaScript and other consumers that read JSON
+    /// numbers as doubles lose precision. Defaults to false, i.e. plain numbers.
+    pub copper_as_string: bool,
+}
+
+/// A copper amount in an export. Serializes as a number or a string depending on
+/// `ExportOptions::copper_as_string`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Copper {
+    Number(u64),
+    String(u64),
+}
+
+impl Copper {
+    fn new(copper: u64, options: &ExportOptions) -> Copper {
+        if options.copper_as_string { Copper::String(copper) } else { Copper::Number(copper) }
+    }
+}
+
+impl Serialize for Copper {
+    fn serialize<S: Serializer>(&self, serializer: &mut S) -> Result<(), S::Error> {
+        match *self {
+            Copper::Number(copper) => serializer.serialize_u64(copper),
+            Copper::String(copper) => serializer.serialize_str(&copper.to_string()),
+        }
+    }
+}
+
+/// A single auction listing as written by the exporters.
+///
+/// Version 1 fields:
+///   - `item_id`: The item's id.
+///   - `buyout_copper`: Whole-stack buyout in copper. 0 if there is no buyout.
+///   - `quantity`: Number of items in the stack.
+///   - `bid`: Whole-stack bid in copper. Omitted if unknown.
+///   - `time_left`: Blizzard's time remaining bucket, e.g. `"LONG"`. Omitted if unknown.
+///
+/// Copper amounts are numbers unless `ExportOptions::copper_as_string` is set.
+#[derive(Debug, Serialize)]
+pub struct ExportedListing {
+    pub item_id: u64,
+    pub buyout_copper: Copper,
+    pub quantity: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bid: Option<Copper>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_left: Option<String>,
+}
+
+impl ExportedListing {
+    pub fn new(listing: &AuctionListing, options: &ExportOptions) -> ExportedListing {
+        ExportedListing {
+            item_id: listing.item,
+            buyout_copper: Copper::new(listing.total_buyout(), options),
+            quantity: listing.quantity,
+            bid: listing.bid.map(|bid| Copper::new(bid, options)),
+            time_left: listing.timeLeft.clone(),
+        }
+    }
+}
+
+impl<'a> From<&'a AuctionListing> for ExportedListing {
+    fn from(listing: &'a AuctionListing) -> ExportedListing {
+        ExportedListing::new(listing, &ExportOptions::default())
+    }
+}
+
+/// The top level object written by `export_json`.
+#[derive(Debug, Serialize)]
+pub struct ExportedListings {
+    pub schema_version: u32,
+    pub listings: Vec<ExportedListing>,
+}
+
+/// Serializes listings as a single JSON document tagged with the schema version.
+pub fn export_json(listings: &[AuctionListing]) -> String {
+    export_json_with(listings, &ExportOptions::default())
+}
+
+/// Like `export_json` but with non-default options.
+pub fn export_json_with(listings: &[AuctionListing], options: &ExportOptions) -> String {
+    let export = ExportedListings {
+        schema_version: LISTING_SCHEMA_VERSION,
+        listings: listings.iter().map(|listing| ExportedListing::new(listing, options)).collect(),
+    };
+    serde_json::to_string(&export).expect("Error serializing listings.")
+}
+
+/// Serializes listings as newline-delimited JSON, one `ExportedListing` per line.
+/// Lines aren't tagged with a schema version; they follow `LISTING_SCHEMA_VERSION`.
+pub fn export_ndjson(listings: &[AuctionListing], options: &ExportOptions) -> String {
+    let mut out = String::new();
+    for listing in listings {
+        out.push_str(&serde_json::to_string(&ExportedListing::new(listing, options))
+            .expect("Error serializing listing."));
+        out.push('\n');
+    }
+    out
+}
+
+/// The hex SHA-256 digest of `content`.
+fn sha256_hex(content: &[u8]) -> String {
+    let mut hasher = Sha256::default();
+    hasher.input(content);
+    let mut hex = String::new();
+    for byte in hasher.result().iter() {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
+
+/// Where the checksum for the export at `path` lives: alongside it, with
+/// `.sha256` appended to the name.
+pub fn checksum_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".sha256");
+    PathBuf::from(name)
+}
+
+/// Writes an export (e.g. the output of `export_json`) to `path`, plus a sidecar
+/// file holding the SHA-256 of its content so `verify_export` can check it later.
+/// The sidecar is written last, so a crash partway through leaves an export that
+/// fails verification rather than one that looks fine.
+pub fn write_export(path: &Path, content: &str) -> io::Result<()> {
+    File::create(path)?.write_all(content.as_bytes())?;
+    File::create(checksum_path(path))?.write_all(sha256_hex(content.as_bytes()).as_bytes())
+}
+
+/// Checks an export written by `write_export` against its sidecar checksum.
+/// Returns false if either file is missing or they don't match.
+pub fn verify_export(path: &Path) -> bool {
+    let mut content = Vec::new();
+    let mut expected = String::new();
+    let read = File::open(path).and_then(|mut file| file.read_to_end(&mut content))
+        .and_then(|_| File::open(checksum_path(path)))
+        .and_then(|mut file| file.read_to_string(&mut expected));
+    read.is_ok() && expected.trim() == sha256_hex(&content)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+
+    use battle_net_api_client::AuctionListing;
+
+    use super::*;
+
+    #[test]
+    fn test_export_json_field_names() {
+        let listings = vec![
+            AuctionListing { item: 124124, buyout: 500, quantity: 5, bid: Some(400), ..Default::default() },
+        ];
+        assert_eq!(export_json(&listings),
+                   "{\"schema_version\":1,\"listings\":[{\"item_id\":124124,\"buyout_copper\":500,\"quantity\":5,\"bid\":400}]}");
+    }
+
+    #[test]
+    fn test_copper_as_string() {
+        let listings = vec![
+            AuctionListing { item: 1, buyout: 9007199254740993, quantity: 1, bid: Some(400), ..Default::default() },
+            AuctionListing { item: 2, buyout: 10, quantity: 1, ..Default::default() },
+        ];
+        let options = ExportOptions { copper_as_string: true };
+        assert_eq!(export_json_with(&listings[..1], &options),
+                   "{\"schema_version\":1,\"listings\":[{\"item_id\":1,\"buyout_copper\":\"9007199254740993\",\"quantity\":1,\"bid\":\"400\"}]}");
+        assert_eq!(export_ndjson(&listings, &ExportOptions::default()),
+                   "{\"item_id\":1,\"buyout_copper\":9007199254740993,\"quantity\":1,\"bid\":400}\n\
+                    {\"item_id\":2,\"buyout_copper\":10,\"quantity\":1}\n");
+    }
+
+    #[test]
+    fn test_verify_export() {
+        let path = env::temp_dir().join("blood-money-test-export.json");
+        let listings = vec![AuctionListing { item: 1, buyout: 10, quantity: 1, ..Default::default() }];
+        write_export(&path, &export_json(&listings)).unwrap();
+        assert!(verify_export(&path));
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(b" ").unwrap();
+        assert!(!verify_export(&path));
+        fs::remove_file(checksum_path(&path)).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(!verify_export(&path));
+    }
+}
@@ -0,0 +1,343 @@
+//! An in-memory cache of item info that can be shared between clients, and
+//! catalog files for keeping a copy of it on disk. Catalog files whose path ends
+//! in `.gz` are gzipped; anything else is plain JSON, which is easier to debug.
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::RwLock;
+
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde_json;
+
+use battle_net_api_client::ItemInfo;
+
+/// Caches item info keyed by locale and item id. Item ids are the same in every
+/// region, so clients for different regions can share one cache, while names are
+/// kept apart per locale.
+///
+/// All methods take `&self` and lock internally, so an `Arc<ItemCache>` can be
+/// used from any number of clients and threads at once. Lookups only take a read
+/// lock. Two clients missing on the same item at the same time will both fetch
+/// it and the second insert wins, which is harmless since they fetched the same data.
+pub struct ItemCache {
+    items: RwLock<HashMap<(String, u64), ItemInfo>>,
+}
+
+impl ItemCache {
+    pub fn new() -> ItemCache {
+        ItemCache {
+            items: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Returns a copy of the cached info for an item in `locale`, if we have it.
+    pub fn get(&self, locale: &str, id: u64) -> Option<ItemInfo> {
+        self.items.read().unwrap().get(&(locale.to_owned(), id)).cloned()
+    }
+
+    /// Caches item info for `locale`, replacing anything already cached for that item.
+    pub fn insert(&self, locale: &str, item_info: ItemInfo) {
+        self.items.write().unwrap().insert((locale.to_owned(), item_info.id), item_info);
+    }
+
+    /// Number of (locale, item) entries in the cache.
+    pub fn len(&self) -> usize {
+        self.items.read().unwrap().len()
+    }
+
+    /// Everything in the cache as catalog entries, ordered by locale then item id.
+    pub fn catalog(&self) -> Vec<CatalogEntry> {
+        let mut entries: Vec<CatalogEntry> = self.items.read().unwrap().iter()
+            .map(|(&(ref locale, _), item_info)| CatalogEntry::new(locale, item_info))
+            .collect();
+        entries.sort_by(|a, b| (&a.locale, a.id).cmp(&(&b.locale, b.id)));
+        entries
+    }
+
+    /// Writes the whole cache to `path` as a single JSON catalog file, which
+    /// `load_catalog` reads back. Gzipped if `path` ends in `.gz`.
+    pub fn export_catalog(&self, path: &Path) -> io::Result<()> {
+        write_json(path, &serde_json::to_string(&self.catalog()).expect("Error serializing catalog."))
+    }
+}
+
+/// One item's info in one locale, as stored in a catalog file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    pub locale: String,
+    pub id: u64,
+    pub name: String,
+    pub icon: String,
+}
+
+impl CatalogEntry {
+    fn new(locale: &str, item_info: &ItemInfo) -> CatalogEntry {
+        CatalogEntry {
+            locale: locale.to_owned(),
+            id: item_info.id,
+            name: item_info.name.clone(),
+            icon: item_info.icon.clone(),
+        }
+    }
+}
+
+/// Reads a catalog written by `ItemCache::export_catalog`, gunzipping it if `path`
+/// ends in `.gz`.
+pub fn load_catalog(path: &Path) -> io::Result<Vec<CatalogEntry>> {
+    serde_json::from_str(&read_json(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Item class and subclass by item id, for the analytics that classify items (see
+/// e.g. `auction_stats::partition_commodities`). Lookups are a hash map away.
+/// Like the item cache it's safe to share between threads, and it's saved to its
+/// own file, gzipped if the path ends in `.gz`.
+pub struct ItemClassifier {
+    classes: RwLock<HashMap<u64, (u64, u64)>>,
+}
+
+/// One item's classification, as stored in a classifier file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct ClassifiedItem {
+    id: u64,
+    class: u64,
+    subclass: u64,
+}
+
+impl ItemClassifier {
+    pub fn new() -> ItemClassifier {
+        ItemClassifier {
+            classes: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Records an item's class and subclass. Item info without them is ignored.
+    pub fn insert(&self, item_info: &ItemInfo) {
+        if let (Some(class), Some(subclass)) = (item_info.item_class, item_info.item_subclass) {
+            self.classes.write().unwrap().insert(item_info.id, (class, subclass));
+        }
+    }
+
+    pub fn class_of(&self, item_id: u64) -> Option<u64> {
+        self.classes.read().unwrap().get(&item_id).map(|&(class, _)| class)
+    }
+
+    pub fn subclass_of(&self, item_id: u64) -> Option<u64> {
+        self.classes.read().unwrap().get(&item_id).map(|&(_, subclass)| subclass)
+    }
+
+    /// Number of items classified.
+    pub fn len(&self) -> usize {
+        self.classes.read().unwrap().len()
+    }
+
+    /// Writes every classification to `path`, which `load` reads back.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut items: Vec<ClassifiedItem> = self.classes.read().unwrap().iter()
+            .map(|(&id, &(class, subclass))| ClassifiedItem { id: id, class: class, subclass: subclass })
+            .collect();
+        items.sort_by_key(|item| item.id);
+        write_json(path, &serde_json::to_string(&items).expect("Error serializing item classes."))
+    }
+
+    /// Reads a classifier written by `save`.
+    pub fn load(path: &Path) -> io::Result<ItemClassifier> {
+        let items: Vec<ClassifiedItem> = serde_json::from_str(&read_json(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(ItemClassifier {
+            classes: RwLock::new(items.into_iter().map(|item| (item.id, (item.class, item.subclass))).collect()),
+        })
+    }
+}
+
+/// Writes `json` to `path`, gzipped if the path ends in `.gz`.
+fn write_json(path: &Path, json: &str) -> io::Result<()> {
+    let file = File::create(path)?;
+    if is_gzipped(path) {
+        let mut encoder = GzEncoder::new(file, Compression::Default);
+        encoder.write_all(json.as_bytes())?;
+        encoder.finish().map(|_| ())
+    } else {
+        let mut file = file;
+        file.write_all(json.as_bytes())
+    }
+}
+
+/// Reads the JSON at `path`, gunzipping it if the path ends in `.gz`.
+fn read_json(path: &Path) -> io::Result<String> {
+    let mut json = String::new();
+    let file = File::open(path)?;
+    if is_gzipped(path) {
+        GzDecoder::new(file)?.read_to_string(&mut json)?;
+    } else {
+        let mut file = file;
+        file.read_to_string(&mut json)?;
+    }
+    Ok(json)
+}
+
+fn is_gzipped(path: &Path) -> bool {
+    path.extension().map_or(false, |extension| extension == "gz")
+}
+
+/// What changed between two catalogs, e.g. from before and after a patch. Entries
+/// are matched up by locale and item id. Each list is ordered by locale then id.
+#[derive(Debug, Default, PartialEq)]
+pub struct CatalogDiff {
+    pub added: Vec<CatalogEntry>,
+    pub removed: Vec<CatalogEntry>,
+    /// (old, new) pairs of entries whose name changed.
+    pub renamed: Vec<(CatalogEntry, CatalogEntry)>,
+    /// (old, new) pairs of entries whose icon changed. An entry whose name and icon
+    /// both changed shows up here and in `renamed`.
+    pub icon_changed: Vec<(CatalogEntry, CatalogEntry)>,
+}
+
+impl CatalogDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.renamed.is_empty() && self.icon_changed.is_empty()
+    }
+}
+
+/// Compares two catalogs.
+pub fn diff_catalog(old: &[CatalogEntry], new: &[CatalogEntry]) -> CatalogDiff {
+    let old_by_key: BTreeMap<(&str, u64), &CatalogEntry> =
+        old.iter().map(|entry| ((&entry.locale[..], entry.id), entry)).collect();
+    let new_by_key: BTreeMap<(&str, u64), &CatalogEntry> =
+        new.iter().map(|entry| ((&entry.locale[..], entry.id), entry)).collect();
+    let mut diff = CatalogDiff::default();
+    for (key, &new_entry) in &new_by_key {
+        match old_by_key.get(key) {
+            None => diff.added.push(new_entry.clone()),
+            Some(&old_entry) => {
+                if old_entry.name != new_entry.name {
+                    diff.renamed.push((old_entry.clone(), new_entry.clone()));
+                }
+                if old_entry.icon != new_entry.icon {
+                    diff.icon_changed.push((old_entry.clone(), new_entry.clone()));
+                }
+            },
+        }
+    }
+    for (key, &old_entry) in &old_by_key {
+        if !new_by_key.contains_key(key) {
+            diff.removed.push(old_entry.clone());
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+    use std::thread;
+
+    use battle_net_api_client::ItemInfo;
+
+    use super::*;
+
+    #[test]
+    fn test_shared_between_threads() {
+        let cache = Arc::new(ItemCache::new());
+        let handles: Vec<_> = (0..4).map(|id| {
+            let cache = cache.clone();
+            thread::spawn(move || {
+                cache.insert("en_US", ItemInfo { id: id, name: format!("item {}", id), icon: "icon".to_owned(),
+                                                  purchase_price: None, item_class: None, item_subclass: None });
+            })
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(cache.len(), 4);
+        assert_eq!(cache.get("en_US", 2).unwrap().name, "item 2");
+        assert!(cache.get("de_DE", 2).is_none());
+    }
+
+    #[test]
+    fn test_catalog_round_trip_and_diff() {
+        let item = |id: u64, name: &str, icon: &str| ItemInfo {
+            id: id,
+            name: name.to_owned(),
+            icon: icon.to_owned(),
+            purchase_price: None,
+            item_class: None,
+            item_subclass: None,
+        };
+        let cache = ItemCache::new();
+        cache.insert("en_US", item(2, "Ore", "inv_ore"));
+        cache.insert("en_US", item(1, "Herb", "inv_herb"));
+        cache.insert("de_DE", item(1, "Kraut", "inv_herb"));
+        let path = env::temp_dir().join("blood-money-test-catalog.json");
+        cache.export_catalog(&path).unwrap();
+        let old = load_catalog(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(old, cache.catalog());
+        assert_eq!(old.iter().map(|entry| (&entry.locale[..], entry.id)).collect::<Vec<_>>(),
+                   vec![("de_DE", 1), ("en_US", 1), ("en_US", 2)]);
+        assert!(diff_catalog(&old, &old).is_empty());
+
+        cache.insert("en_US", item(1, "Herb", "inv_herb_new"));
+        cache.insert("en_US", item(2, "Rusty Ore", "inv_ore"));
+        cache.insert("en_US", item(3, "Gem", "inv_gem"));
+        let mut new = cache.catalog();
+        new.retain(|entry| entry.locale != "de_DE");
+        let diff = diff_catalog(&old, &new);
+        assert_eq!(diff.added.iter().map(|entry| entry.id).collect::<Vec<u64>>(), vec![3]);
+        assert_eq!(diff.removed.iter().map(|entry| &entry.name[..]).collect::<Vec<&str>>(), vec!["Kraut"]);
+        assert_eq!(diff.renamed.iter().map(|&(ref old, ref new)| (&old.name[..], &new.name[..])).collect::<Vec<_>>(),
+                   vec![("Ore", "Rusty Ore")]);
+        assert_eq!(diff.icon_changed.iter().map(|&(_, ref new)| &new.icon[..]).collect::<Vec<&str>>(),
+                   vec!["inv_herb_new"]);
+    }
+
+    #[test]
+    fn test_gzipped_catalog() {
+        let cache = ItemCache::new();
+        for id in 0..100 {
+            cache.insert("en_US", ItemInfo {
+                id: id,
+                name: "Blood of Sargeras".to_owned(),
+                icon: "inv_blood".to_owned(),
+                purchase_price: None,
+                item_class: None,
+                item_subclass: None,
+            });
+        }
+        let plain = env::temp_dir().join("blood-money-test-catalog-plain.json");
+        let gzipped = env::temp_dir().join("blood-money-test-catalog.json.gz");
+        cache.export_catalog(&plain).unwrap();
+        cache.export_catalog(&gzipped).unwrap();
+        assert!(fs::metadata(&gzipped).unwrap().len() < fs::metadata(&plain).unwrap().len() / 4);
+        assert_eq!(load_catalog(&gzipped).unwrap(), cache.catalog());
+        fs::remove_file(&plain).unwrap();
+        fs::remove_file(&gzipped).unwrap();
+    }
+
+    #[test]
+    fn test_item_classifier() {
+        let classifier = ItemClassifier::new();
+        classifier.insert(&ItemInfo {
+            id: 124124,
+            name: "Blood of Sargeras".to_owned(),
+            icon: String::new(),
+            purchase_price: None,
+            item_class: Some(7),
+            item_subclass: Some(5),
+        });
+        classifier.insert(&ItemInfo { id: 1, name: String::new(), icon: String::new(), purchase_price: None,
+                                      item_class: None, item_subclass: None });
+        assert_eq!((classifier.class_of(124124), classifier.subclass_of(124124)), (Some(7), Some(5)));
+        assert_eq!(classifier.class_of(1), None);
+        let path = env::temp_dir().join("blood-money-test-classes.json.gz");
+        classifier.save(&path).unwrap();
+        let loaded = ItemClassifier::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded.subclass_of(124124), Some(5));
+    }
+}
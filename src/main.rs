@@ -1,16 +1,34 @@
 #![feature(proc_macro)]
+#![feature(i128_type)]
+#![cfg_attr(test, feature(test))]
 
+extern crate flate2;
 extern crate hyper;
+#[cfg(feature = "tls")]
+extern crate hyper_native_tls;
 extern crate iron;
+#[cfg(feature = "tls")]
+extern crate native_tls;
+#[cfg(feature = "parquet")]
+extern crate parquet;
+#[cfg(feature = "prometheus")]
+extern crate prometheus;
 extern crate regex;
 extern crate router;
+#[cfg(feature = "rustc-serialize")]
 extern crate rustc_serialize;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
 extern crate scoped_threadpool;
+extern crate sha2;
 extern crate tera;
+#[cfg(test)]
+extern crate test;
+#[cfg(feature = "tracing")]
+#[macro_use]
+extern crate tracing;
 
 use std::collections::{BTreeMap, HashMap};
 use std::env;
@@ -25,10 +43,27 @@ use router::Router;
 use scoped_threadpool::Pool;
 use tera::{Context, Tera};
 
+pub mod archive;
+pub mod auction_decoder;
+pub mod auction_stats;
 pub mod battle_net_api_client;
+pub mod clock;
+pub mod export;
+pub mod item_cache;
+pub mod metrics;
+pub mod multi_region;
+pub mod oauth;
+#[cfg(feature = "parquet")]
+pub mod parquet_export;
+pub mod price_history;
+pub mod realms;
+pub mod report;
+pub mod scrape;
 pub mod thread_throttler;
+#[cfg(feature = "tls")]
+pub mod tls;
 
-use battle_net_api_client::{AuctionListing, BattleNetApiClient};
+use battle_net_api_client::{AuctionListing, BattleNetApiClient, BattleNetApiError, ItemInfo};
 
 /// Represents a single option available for sale from the blood vendor.
 #[derive(Debug, Deserialize)]
@@ -77,8 +112,8 @@ fn calculate_auction_values(listings: &Vec<AuctionListing>, items: &HashMap<u64,
     // Calculate 5th percentiles for the items we care about.
     let mut price_points: BTreeMap<u64, Vec<(u64, u64)>> = BTreeMap::new();
     for listing in listings {
-        if items.contains_key(&listing.item) && listing.buyout > 0 {
-            price_points.entry(listing.item).or_insert(Vec::new()).push((listing.quantity, listing.buyout / listing.quantity));
+        if items.contains_key(&listing.item) && listing.unit_price() > 0 {
+            price_points.entry(listing.item).or_insert(Vec::new()).push((listing.quantity, listing.unit_price()));
         }
     }
     for quantities_and_buyouts in price_points.values_mut() {
@@ -121,11 +156,18 @@ fn main() {
     let items: Vec<BloodVendorItem> = serde_json::from_str(include_str!("../catalog/items.json"))
         .expect("Error reading items.");
     let item_id_map: Arc<HashMap<u64, BloodVendorItem>> = Arc::new(items.into_iter().map(|x| (x.id, x)).collect());
-    let item_icons: Arc<HashMap<u64, String>> = Arc::new(item_id_map.keys().map(|&id| (id, client.get_item_info(id).icon)).collect());
+    // Items Blizzard has since removed just go without an icon.
+    let item_icons: Arc<HashMap<u64, String>> = Arc::new(item_id_map.keys().map(|&id| {
+        let item_info = match client.get_item_info(id) {
+            Err(BattleNetApiError::ItemNotFound(_)) => ItemInfo::placeholder(id),
+            result => result.expect("Error reading item info."),
+        };
+        (id, item_info.icon)
+    }).collect());
 
     // Get the list of realms and create an empty price map so we can render pages while
     // waiting for the auction results to be retrieved.
-    let realms = Arc::new(client.get_realms());
+    let realms = Arc::new(client.get_realms().expect("Error reading realms."));
     let connected_realms = BattleNetApiClient::process_connected_realms(&realms);
     let price_map: Arc<BTreeMap<String, RwLock<CurrentRealmValues>>> =
         Arc::new(realms.iter().map(|realm| (realm.slug.clone(), RwLock::new(CurrentRealmValues {
@@ -219,11 +261,15 @@ fn main() {
                         let current_realm_values =
                             price_map.get(lead_realm).unwrap().read().unwrap();
                         match client.get_auction_listings(lead_realm, current_realm_values.last_update) {
-                            Some((ts, al)) => {
+                            Ok(Some((ts, al))) => {
                                 update_time = ts;
                                 auction_listings = al;
                             },
-                            None => return,
+                            Ok(None) => return,
+                            Err(e) => {
+                                println!("Failed to download {}: {}", lead_realm, e);
+                                return;
+                            },
                         }
                     }
                     let auction_values = Arc::new(calculate_auction_values(&auction_listings, &item_id_map));
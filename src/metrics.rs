@@ -0,0 +1,173 @@
+//! Counters describing what a client has been up to, for monitoring long running
+//! scrapers. Every client keeps a `ClientMetrics`; `BattleNetApiClient::metrics`
+//! returns a snapshot of it. With the `prometheus` feature the same counters can
+//! also be registered with a Prometheus registry.
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "prometheus")]
+use prometheus::{self, Counter, Gauge, Registry};
+
+/// The counters at one point in time.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    /// Requests sent, retries included.
+    pub requests: u64,
+    /// Failed attempts that were retried.
+    pub retries: u64,
+    /// Response body bytes received.
+    pub bytes_downloaded: u64,
+    /// Responses that didn't decode.
+    pub decode_errors: u64,
+    /// When a call last succeeded, in seconds since the epoch.
+    pub last_successful_fetch: Option<u64>,
+}
+
+/// A client's counters. Updates are lock free, so one `ClientMetrics` can be
+/// shared between several clients (see `BattleNetApiClientBuilder::metrics`) to
+/// count them all together.
+pub struct ClientMetrics {
+    requests: AtomicUsize,
+    retries: AtomicUsize,
+    bytes_downloaded: AtomicUsize,
+    decode_errors: AtomicUsize,
+    last_successful_fetch: AtomicUsize,
+    #[cfg(feature = "prometheus")]
+    prometheus: Option<PrometheusMetrics>,
+}
+
+impl ClientMetrics {
+    #[cfg(not(feature = "prometheus"))]
+    pub fn new() -> ClientMetrics {
+        ClientMetrics {
+            requests: AtomicUsize::new(0),
+            retries: AtomicUsize::new(0),
+            bytes_downloaded: AtomicUsize::new(0),
+            decode_errors: AtomicUsize::new(0),
+            last_successful_fetch: AtomicUsize::new(0),
+        }
+    }
+
+    #[cfg(feature = "prometheus")]
+    pub fn new() -> ClientMetrics {
+        ClientMetrics {
+            requests: AtomicUsize::new(0),
+            retries: AtomicUsize::new(0),
+            bytes_downloaded: AtomicUsize::new(0),
+            decode_errors: AtomicUsize::new(0),
+            last_successful_fetch: AtomicUsize::new(0),
+            prometheus: None,
+        }
+    }
+
+    /// Like `new`, but also registers the counters with `registry`, as
+    /// `blood_money_requests_total`, `blood_money_retries_total`,
+    /// `blood_money_bytes_downloaded_total`, `blood_money_decode_errors_total` and
+    /// `blood_money_last_successful_fetch_timestamp`. Fails if they're already
+    /// registered, e.g. by another `ClientMetrics`; share one instead.
+    #[cfg(feature = "prometheus")]
+    pub fn with_prometheus(registry: &Registry) -> prometheus::Result<ClientMetrics> {
+        let mut metrics = ClientMetrics::new();
+        metrics.prometheus = Some(PrometheusMetrics::register(registry)?);
+        Ok(metrics)
+    }
+
+    pub fn record_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "prometheus")]
+        self.with_prometheus_metrics(|metrics| metrics.requests.inc());
+    }
+
+    /// Records a request unless `limit` requests have already been recorded, in
+    /// which case nothing changes and false is returned. Safe to race: concurrent
+    /// callers never take the count past `limit`.
+    pub fn try_record_request(&self, limit: u64) -> bool {
+        let mut current = self.requests.load(Ordering::SeqCst);
+        loop {
+            if current as u64 >= limit {
+                return false;
+            }
+            match self.requests.compare_exchange(current, current + 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => break,
+                Err(actual) => current = actual,
+            }
+        }
+        #[cfg(feature = "prometheus")]
+        self.with_prometheus_metrics(|metrics| metrics.requests.inc());
+        true
+    }
+
+    pub fn record_retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "prometheus")]
+        self.with_prometheus_metrics(|metrics| metrics.retries.inc());
+    }
+
+    pub fn record_bytes(&self, bytes: usize) {
+        self.bytes_downloaded.fetch_add(bytes, Ordering::Relaxed);
+        #[cfg(feature = "prometheus")]
+        self.with_prometheus_metrics(|metrics| { let _ = metrics.bytes_downloaded.inc_by(bytes as f64); });
+    }
+
+    pub fn record_decode_error(&self) {
+        self.decode_errors.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "prometheus")]
+        self.with_prometheus_metrics(|metrics| metrics.decode_errors.inc());
+    }
+
+    /// Records that a call succeeded at `now`.
+    pub fn record_success(&self, now: SystemTime) {
+        let secs = now.duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+        self.last_successful_fetch.store(secs as usize, Ordering::Relaxed);
+        #[cfg(feature = "prometheus")]
+        self.with_prometheus_metrics(|metrics| metrics.last_successful_fetch.set(secs as f64));
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let last_successful_fetch = self.last_successful_fetch.load(Ordering::Relaxed) as u64;
+        MetricsSnapshot {
+            requests: self.requests.load(Ordering::Relaxed) as u64,
+            retries: self.retries.load(Ordering::Relaxed) as u64,
+            bytes_downloaded: self.bytes_downloaded.load(Ordering::Relaxed) as u64,
+            decode_errors: self.decode_errors.load(Ordering::Relaxed) as u64,
+            last_successful_fetch: if last_successful_fetch == 0 { None } else { Some(last_successful_fetch) },
+        }
+    }
+
+    #[cfg(feature = "prometheus")]
+    fn with_prometheus_metrics<F: Fn(&PrometheusMetrics)>(&self, f: F) {
+        if let Some(ref metrics) = self.prometheus {
+            f(metrics);
+        }
+    }
+}
+
+/// The Prometheus side of a `ClientMetrics`.
+#[cfg(feature = "prometheus")]
+struct PrometheusMetrics {
+    requests: Counter,
+    retries: Counter,
+    bytes_downloaded: Counter,
+    decode_errors: Counter,
+    last_successful_fetch: Gauge,
+}
+
+#[cfg(feature = "prometheus")]
+impl PrometheusMetrics {
+    fn register(registry: &Registry) -> prometheus::Result<PrometheusMetrics> {
+        let metrics = PrometheusMetrics {
+            requests: Counter::new("blood_money_requests_total", "Requests sent to Blizzard, retries included.")?,
+            retries: Counter::new("blood_money_retries_total", "Failed requests that were retried.")?,
+            bytes_downloaded: Counter::new("blood_money_bytes_downloaded_total", "Response body bytes received.")?,
+            decode_errors: Counter::new("blood_money_decode_errors_total", "Responses that failed to decode.")?,
+            last_successful_fetch: Gauge::new("blood_money_last_successful_fetch_timestamp",
+                                              "When a call last succeeded, in seconds since the epoch.")?,
+        };
+        registry.register(Box::new(metrics.requests.clone()))?;
+        registry.register(Box::new(metrics.retries.clone()))?;
+        registry.register(Box::new(metrics.bytes_downloaded.clone()))?;
+        registry.register(Box::new(metrics.decode_errors.clone()))?;
+        registry.register(Box::new(metrics.last_successful_fetch.clone()))?;
+        Ok(metrics)
+    }
+}
@@ -0,0 +1,127 @@
+//! One client per region, for tools that cover several regions at once.
+use std::collections::BTreeMap;
+
+use hyper::client::Client;
+
+use battle_net_api_client::{AuctionListing, BattleNetApiClient, BattleNetApiError, HyperTransport, RealmInfo, Region,
+                            TokenPrice};
+use oauth::{ClientFactory, TokenRequestConfig, fetch_access_token_with_client};
+
+pub struct MultiRegionClient {
+    clients: BTreeMap<Region, BattleNetApiClient>,
+}
+
+impl MultiRegionClient {
+    /// Wraps already configured clients, one per region. If two clients are for
+    /// the same region the later one wins.
+    pub fn from_clients(clients: Vec<BattleNetApiClient>) -> MultiRegionClient {
+        MultiRegionClient {
+            clients: clients.into_iter().map(|client| (client.region(), client)).collect(),
+        }
+    }
+
+    /// Builds a client for each of `regions` from one set of OAuth client
+    /// credentials, which work in every region. Each region still gets its own
+    /// access token and hosts.
+    pub fn from_credentials(client_id: &str, client_secret: &str, regions: &[Region], config: &TokenRequestConfig)
+            -> Result<MultiRegionClient, BattleNetApiError> {
+        MultiRegionClient::from_credentials_with_clients(client_id, client_secret, regions, config, &Client::new)
+    }
+
+    /// Like `from_credentials` but every token request and every region's client
+    /// go through hyper clients from `make_client`, e.g. to use a custom TLS setup
+    /// (see `tls::tls_client_factory`).
+    pub fn from_credentials_with_clients(client_id: &str, client_secret: &str, regions: &[Region],
+                                         config: &TokenRequestConfig, make_client: &ClientFactory)
+            -> Result<MultiRegionClient, BattleNetApiError> {
+        let mut clients = Vec::new();
+        for &region in regions {
+            let token = fetch_access_token_with_client(make_client(), region, client_id, client_secret, config)?;
+            clients.push(BattleNetApiClient::builder(&token.access_token)
+                .region(region)
+                .transport(Box::new(HyperTransport::with_client(make_client())))
+                .build());
+        }
+        Ok(MultiRegionClient::from_clients(clients))
+    }
+
+    /// The client for `region`, if there is one.
+    pub fn client(&self, region: Region) -> Option<&BattleNetApiClient> {
+        self.clients.get(&region)
+    }
+
+    /// The regions this client covers, in order.
+    pub fn regions(&self) -> Vec<Region> {
+        self.clients.keys().cloned().collect()
+    }
+
+    /// Returns every region's realm list. Fails if any region's does.
+    pub fn get_all_realms(&self) -> Result<BTreeMap<Region, Vec<RealmInfo>>, BattleNetApiError> {
+        let mut realms = BTreeMap::new();
+        for (&region, client) in &self.clients {
+            realms.insert(region, client.get_realms()?);
+        }
+        Ok(realms)
+    }
+
+    /// Every region's current WoW Token price. Fails if any region's does.
+    pub fn get_token_prices(&self) -> Result<BTreeMap<Region, TokenPrice>, BattleNetApiError> {
+        let mut prices = BTreeMap::new();
+        for (&region, client) in &self.clients {
+            prices.insert(region, client.get_wow_token_price()?);
+        }
+        Ok(prices)
+    }
+
+    /// `get_auction_listings` for a realm in `region`.
+    /// Panics if this doesn't cover `region`.
+    pub fn get_auction_listings(&self, region: Region, realm_slug: &str, cutoff: u64)
+            -> Result<Option<(u64, Vec<AuctionListing>)>, BattleNetApiError> {
+        self.clients.get(&region).expect("No client for region.").get_auction_listings(realm_slug, cutoff)
+    }
+}
+
+/// Converts an item's gold price in each region to WoW Tokens, the one thing
+/// that costs the same real money everywhere, so prices can be compared across
+/// regions. Takes (token price, item price) per region, both in copper, and
+/// returns how many tokens the item costs in each. Regions with a token price of 0
+/// are left out.
+pub fn token_normalized_prices(prices: &BTreeMap<Region, (u64, u64)>) -> BTreeMap<Region, f64> {
+    prices.iter()
+        .filter(|&(_, &(token_price, _))| token_price > 0)
+        .map(|(&region, &(token_price, item_price))| (region, item_price as f64 / token_price as f64))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use battle_net_api_client::{BattleNetApiClient, Region};
+
+    use super::*;
+
+    #[test]
+    fn test_from_clients() {
+        let multi = MultiRegionClient::from_clients(vec![
+            BattleNetApiClient::builder("eu").region(Region::Eu).build(),
+            BattleNetApiClient::builder("us").region(Region::Us).build(),
+            BattleNetApiClient::builder("eu2").region(Region::Eu).build(),
+        ]);
+        assert_eq!(multi.regions(), vec![Region::Us, Region::Eu]);
+        assert_eq!(multi.client(Region::Eu).unwrap().token, "eu2");
+        assert!(multi.client(Region::Kr).is_none());
+    }
+
+    #[test]
+    fn test_token_normalized_prices() {
+        let mut prices = BTreeMap::new();
+        prices.insert(Region::Us, (2_000_000_000, 500_000_000));
+        prices.insert(Region::Eu, (1_000_000_000, 500_000_000));
+        prices.insert(Region::Kr, (0, 1));
+        let normalized = token_normalized_prices(&prices);
+        assert_eq!(normalized.len(), 2);
+        assert_eq!(normalized[&Region::Us], 0.25);
+        assert_eq!(normalized[&Region::Eu], 0.5);
+    }
+}
@@ -0,0 +1,139 @@
+//! Getting an OAuth access token for the game data API using the client
+//! credentials flow. The token request deliberately doesn't share the data calls'
+//! `RetryConfig`: if the credentials are wrong we want to hear about it straight
+//! away, not retry forever at startup.
+use std::io::Read;
+use std::thread::sleep;
+use std::time::Duration;
+
+use hyper::client::Client;
+use hyper::header::{Authorization, Basic, ContentType};
+use hyper::mime::{Mime, SubLevel, TopLevel};
+use hyper::status::StatusCode;
+
+use battle_net_api_client::{BattleNetApiError, Region, decode_json};
+
+/// Limits for the token request.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenRequestConfig {
+    /// Read/write timeout for the request.
+    pub timeout: Duration,
+    /// How many times to retry after a network error or server error. Rejected
+    /// credentials are never retried.
+    pub max_retries: u32,
+    /// How long to wait between retries.
+    pub retry_delay: Duration,
+}
+
+impl Default for TokenRequestConfig {
+    fn default() -> TokenRequestConfig {
+        TokenRequestConfig {
+            timeout: Duration::from_secs(10),
+            max_retries: 2,
+            retry_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Makes a fresh hyper client each time it's called, for callers that need
+/// several clients set up the same way, e.g. with `tls::tls_client_factory`.
+/// `Client::new` is the default.
+pub type ClientFactory = Fn() -> Client + Send + Sync;
+
+/// An access token, as returned by the token endpoint.
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(feature = "rustc-serialize", derive(RustcDecodable))]
+pub struct AccessToken {
+    pub access_token: String,
+    /// Seconds until the token expires.
+    pub expires_in: u64,
+}
+
+/// The body the token endpoint sends when it refuses a request.
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "rustc-serialize", derive(RustcDecodable))]
+struct OAuthError {
+    error: String,
+    error_description: Option<String>,
+}
+
+/// The URL of a region's token endpoint.
+pub fn token_url(region: Region) -> String {
+    match region {
+        Region::Cn => "https://www.battlenet.com.cn/oauth/token".to_owned(),
+        _ => format!("https://{}.battle.net/oauth/token", region.namespace_suffix()),
+    }
+}
+
+/// Requests an access token for `client_id` and `client_secret`. Pass its
+/// `access_token` to `BattleNetApiClient::new` to use the game data API.
+/// Rejected credentials (e.g. `invalid_client`) come back as an `AuthError`
+/// immediately.
+pub fn fetch_access_token(region: Region, client_id: &str, client_secret: &str, config: &TokenRequestConfig)
+        -> Result<AccessToken, BattleNetApiError> {
+    fetch_access_token_with_client(Client::new(), region, client_id, client_secret, config)
+}
+
+/// Like `fetch_access_token` but sends the request with `client`, e.g. one from
+/// `tls::tls_client`. Its timeouts are overwritten with `config`'s.
+pub fn fetch_access_token_with_client(mut client: Client, region: Region, client_id: &str, client_secret: &str,
+                                      config: &TokenRequestConfig) -> Result<AccessToken, BattleNetApiError> {
+    client.set_read_timeout(Some(config.timeout));
+    client.set_write_timeout(Some(config.timeout));
+    let url = token_url(region);
+    let mut retries = 0;
+    loop {
+        match try_fetch_access_token(&client, &url, client_id, client_secret) {
+            Err(e @ BattleNetApiError::AuthError(_)) => return Err(e),
+            Err(e) => {
+                if retries >= config.max_retries {
+                    return Err(e);
+                }
+                retries += 1;
+                sleep(config.retry_delay);
+            },
+            token => return token,
+        }
+    }
+}
+
+fn try_fetch_access_token(client: &Client, url: &str, client_id: &str, client_secret: &str)
+        -> Result<AccessToken, BattleNetApiError> {
+    let mut res = match client.post(url)
+        .header(Authorization(Basic { username: client_id.to_owned(), password: Some(client_secret.to_owned()) }))
+        .header(ContentType(Mime(TopLevel::Application, SubLevel::WwwFormUrlEncoded, vec![])))
+        .body("grant_type=client_credentials")
+        .send() {
+        Ok(r) => r,
+        Err(e) => return Err(BattleNetApiError::NetworkError(e)),
+    };
+    let mut body = String::new();
+    if let Err(e) = res.read_to_string(&mut body) {
+        return Err(BattleNetApiError::ReadError(e));
+    }
+    match res.status {
+        StatusCode::Ok => decode_json(&body).map_err(|e| BattleNetApiError::DecodeError(e, body.clone())),
+        StatusCode::BadRequest | StatusCode::Unauthorized | StatusCode::Forbidden => {
+            let reason = match decode_json::<OAuthError>(&body) {
+                Ok(OAuthError { error, error_description: Some(description) }) => format!("{}: {}", error, description),
+                Ok(OAuthError { error, error_description: None }) => error,
+                Err(_) => res.status.to_string(),
+            };
+            Err(BattleNetApiError::AuthError(reason))
+        },
+        status => Err(BattleNetApiError::HttpStatus(status, None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use battle_net_api_client::Region;
+
+    use super::*;
+
+    #[test]
+    fn test_token_url() {
+        assert_eq!(token_url(Region::Eu), "https://eu.battle.net/oauth/token");
+        assert_eq!(token_url(Region::Cn), "https://www.battlenet.com.cn/oauth/token");
+    }
+}
@@ -0,0 +1,74 @@
+//! Writing per-item price series as Parquet, for loading straight into pandas,
+//! polars and friends. Needs the `parquet` feature.
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+use std::rc::Rc;
+
+use parquet::column::writer::ColumnWriter;
+use parquet::errors::Result;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{FileWriter, RowGroupWriter, SerializedFileWriter};
+use parquet::schema::parser::parse_message_type;
+
+const PRICE_SERIES_SCHEMA: &'static str = "
+    message price_series {
+        REQUIRED INT64 item_id;
+        REQUIRED INT64 timestamp;
+        REQUIRED INT64 price;
+    }
+";
+
+/// Writes snapshots of per-unit prices, as (timestamp, item id -> price in copper),
+/// to a Parquet file at `path` with one row per item per snapshot and the columns
+/// `item_id`, `timestamp` and `price`, all INT64. Rows are in snapshot order, then
+/// item order. Returns how many rows were written.
+pub fn write_price_series(path: &Path, series: &[(u64, BTreeMap<u64, u64>)]) -> Result<usize> {
+    let mut item_ids = Vec::new();
+    let mut timestamps = Vec::new();
+    let mut prices = Vec::new();
+    for &(timestamp, ref snapshot) in series {
+        for (&item_id, &price) in snapshot {
+            item_ids.push(item_id as i64);
+            timestamps.push(timestamp as i64);
+            prices.push(price as i64);
+        }
+    }
+
+    let schema = Rc::new(parse_message_type(PRICE_SERIES_SCHEMA)?);
+    let properties = Rc::new(WriterProperties::builder().build());
+    let mut writer = SerializedFileWriter::new(File::create(path)?, schema, properties)?;
+    let mut row_group = writer.next_row_group()?;
+    for column in &[&item_ids, &timestamps, &prices] {
+        let mut column_writer = row_group.next_column()?.expect("Schema has three columns.");
+        if let ColumnWriter::Int64ColumnWriter(ref mut typed) = column_writer {
+            typed.write_batch(column, None, None)?;
+        }
+        row_group.close_column(column_writer)?;
+    }
+    writer.close_row_group(row_group)?;
+    writer.close()?;
+    Ok(item_ids.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Read;
+
+    use super::*;
+
+    #[test]
+    fn test_write_price_series() {
+        let path = env::temp_dir().join("blood-money-test-prices.parquet");
+        let first: BTreeMap<u64, u64> = vec![(1, 100), (2, 250)].into_iter().collect();
+        let second: BTreeMap<u64, u64> = vec![(1, 110)].into_iter().collect();
+        assert_eq!(write_price_series(&path, &[(1000, first), (2000, second)]).unwrap(), 3);
+        let mut magic = [0; 4];
+        File::open(&path).unwrap().read_exact(&mut magic).unwrap();
+        assert_eq!(&magic, b"PAR1");
+        fs::remove_file(&path).unwrap();
+    }
+}
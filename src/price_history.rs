@@ -0,0 +1,426 @@
+//! Accumulators that remember prices across several auction snapshots, e.g.
+//! over a session of repeated scrapes. Prices are per-unit copper.
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+use auction_stats::{distinct_item_ids, min_buyout};
+use battle_net_api_client::{AuctionListing, TokenPrice};
+
+/// The lowest and highest per-unit buyout seen for each item. Items whose
+/// price swings a lot between snapshots tend to be good flip candidates.
+#[derive(Debug, Default, Clone)]
+pub struct PriceExtremes {
+    ranges: BTreeMap<u64, (u64, u64)>,
+}
+
+impl PriceExtremes {
+    pub fn new() -> PriceExtremes {
+        PriceExtremes::default()
+    }
+
+    /// Widens each item's range to cover the per-unit buyouts in `listings`.
+    /// Listings without a buyout are ignored.
+    pub fn observe(&mut self, listings: &[AuctionListing]) {
+        for listing in listings {
+            let unit_price = listing.unit_price();
+            if unit_price == 0 {
+                continue;
+            }
+            let range = self.ranges.entry(listing.item).or_insert((unit_price, unit_price));
+            range.0 = cmp::min(range.0, unit_price);
+            range.1 = cmp::max(range.1, unit_price);
+        }
+    }
+
+    /// Returns `(min, max)` per-unit price seen for an item, or None if it was never seen.
+    pub fn range(&self, item_id: u64) -> Option<(u64, u64)> {
+        self.ranges.get(&item_id).cloned()
+    }
+}
+
+/// What a `PriceHistory` does with prices that fall out of its full resolution
+/// window.
+#[derive(Debug, Clone, Copy)]
+pub struct Retention {
+    /// Older prices are averaged into buckets this wide, e.g. a day.
+    pub bucket: Duration,
+    /// Buckets starting more than this long before the newest recorded snapshot are
+    /// dropped by `PriceHistory::prune`.
+    pub max_age: Duration,
+}
+
+/// Per-item price statistics over a series of snapshots: an exponential moving
+/// average, plus the most recent prices for robust stats like `rolling_median`.
+#[derive(Debug, Clone)]
+pub struct PriceHistory {
+    alpha: f64,
+    max_window: usize,
+    retention: Option<Retention>,
+    latest: u64,
+    ema: BTreeMap<u64, f64>,
+    /// The last `max_window` (timestamp, price) points per item, oldest first.
+    recent: BTreeMap<u64, VecDeque<(u64, u64)>>,
+    /// Per item, (sum, count) of the older prices by bucket start.
+    downsampled: BTreeMap<u64, BTreeMap<u64, (u128, u64)>>,
+}
+
+impl PriceHistory {
+    /// A history whose EMA gives each new price a weight of `alpha` (between 0 and
+    /// 1), and which keeps the last `max_window` prices of every item, so memory use
+    /// is bounded by the number of items times `max_window`.
+    pub fn new(alpha: f64, max_window: usize) -> PriceHistory {
+        assert!(alpha > 0.0 && alpha <= 1.0, "Alpha must be in (0, 1].");
+        assert!(max_window > 0, "Window must be positive.");
+        PriceHistory {
+            alpha: alpha,
+            max_window: max_window,
+            retention: None,
+            latest: 0,
+            ema: BTreeMap::new(),
+            recent: BTreeMap::new(),
+            downsampled: BTreeMap::new(),
+        }
+    }
+
+    /// Keeps prices that fall out of the `max_window` most recent at a lower
+    /// resolution instead of discarding them, averaged per `retention.bucket`.
+    /// Call `prune` now and then to drop what's older than `retention.max_age`.
+    pub fn retention(mut self, retention: Retention) -> PriceHistory {
+        assert!(millis(retention.bucket) > 0, "Bucket must be at least a millisecond.");
+        self.retention = Some(retention);
+        self
+    }
+
+    /// Records a snapshot's per-unit prices (item id -> price), taken at `timestamp`.
+    pub fn record(&mut self, timestamp: u64, prices: &BTreeMap<u64, u64>) {
+        for (&item, &price) in prices {
+            let alpha = self.alpha;
+            let ema = self.ema.entry(item).or_insert(price as f64);
+            *ema = alpha * price as f64 + (1.0 - alpha) * *ema;
+            let recent = self.recent.entry(item).or_insert(VecDeque::new());
+            if recent.len() == self.max_window {
+                if let (Some((old_timestamp, old_price)), Some(retention)) = (recent.pop_front(), self.retention) {
+                    let bucket_ms = millis(retention.bucket);
+                    let bucket = self.downsampled.entry(item).or_insert(BTreeMap::new())
+                        .entry(old_timestamp - old_timestamp % bucket_ms).or_insert((0, 0));
+                    bucket.0 += old_price as u128;
+                    bucket.1 += 1;
+                }
+            }
+            recent.push_back((timestamp, price));
+        }
+        self.latest = cmp::max(self.latest, timestamp);
+    }
+
+    /// Drops downsampled buckets older than the retention's `max_age`, measured back
+    /// from the newest snapshot recorded. Does nothing without a retention policy.
+    pub fn prune(&mut self) {
+        let retention = match self.retention {
+            Some(retention) => retention,
+            None => return,
+        };
+        let oldest = self.latest.saturating_sub(millis(retention.max_age));
+        for buckets in self.downsampled.values_mut() {
+            let expired: Vec<u64> = buckets.keys().cloned().take_while(|&start| start < oldest).collect();
+            for start in expired {
+                buckets.remove(&start);
+            }
+        }
+        let emptied: Vec<u64> = self.downsampled.iter()
+            .filter(|&(_, buckets)| buckets.is_empty())
+            .map(|(&item, _)| item)
+            .collect();
+        for item in emptied {
+            self.downsampled.remove(&item);
+        }
+    }
+
+    /// The item's older prices averaged per bucket, as (bucket start, average)
+    /// oldest first. Empty without a retention policy.
+    pub fn downsampled(&self, item_id: u64) -> Vec<(u64, u64)> {
+        self.downsampled.get(&item_id).map_or(Vec::new(), |buckets| {
+            buckets.iter().map(|(&start, &(sum, count))| (start, (sum / count as u128) as u64)).collect()
+        })
+    }
+
+    /// Records every item's `min_buyout` in a snapshot's listings.
+    pub fn observe(&mut self, timestamp: u64, listings: &[AuctionListing]) {
+        let prices: BTreeMap<u64, u64> = distinct_item_ids(listings).into_iter()
+            .filter_map(|item| min_buyout(listings, item).map(|price| (item, price)))
+            .collect();
+        self.record(timestamp, &prices);
+    }
+
+    /// The item's exponential moving average price, or None if it was never seen.
+    pub fn ema(&self, item_id: u64) -> Option<u64> {
+        self.ema.get(&item_id).map(|&ema| ema.round() as u64)
+    }
+
+    /// The median of the item's last `window` prices (fewer if fewer were recorded,
+    /// and at most the history's `max_window`). Unlike the EMA a few mispriced
+    /// snapshots don't move it. With an even count it's the mean of the middle two.
+    pub fn rolling_median(&self, item_id: u64, window: usize) -> Option<u64> {
+        let recent = match self.recent.get(&item_id) {
+            Some(recent) if window > 0 => recent,
+            _ => return None,
+        };
+        let mut prices: Vec<u64> = recent.iter().rev().take(window).map(|&(_, price)| price).collect();
+        prices.sort();
+        let middle = prices.len() / 2;
+        if prices.len() % 2 == 0 {
+            Some(((prices[middle - 1] as u128 + prices[middle] as u128) / 2) as u64)
+        } else {
+            Some(prices[middle])
+        }
+    }
+
+    /// The range an item's price normally stays in, as (low, high), from all of its
+    /// recent prices: the quartiles widened by 1.5 times the interquartile range
+    /// (Tukey's fences). A min buyout below `low` is worth a buyer's look, a market
+    /// above `high` a seller's. None until `MIN_BAND_SAMPLES` prices are recorded.
+    pub fn value_band(&self, item_id: u64) -> Option<(u64, u64)> {
+        let mut prices: Vec<u64> = match self.recent.get(&item_id) {
+            Some(recent) if recent.len() >= MIN_BAND_SAMPLES => recent.iter().map(|&(_, price)| price).collect(),
+            _ => return None,
+        };
+        prices.sort();
+        let q1 = prices[prices.len() / 4];
+        let q3 = prices[prices.len() * 3 / 4];
+        let margin = (q3 - q1).saturating_mul(3) / 2;
+        Some((q1.saturating_sub(margin), q3.saturating_add(margin)))
+    }
+}
+
+/// How many recent prices `PriceHistory::value_band` needs before it gives a band.
+pub const MIN_BAND_SAMPLES: usize = 5;
+
+/// WoW Token prices collected from repeated `get_wow_token_price` calls, for
+/// normalizing item prices against the value of gold over time.
+#[derive(Debug, Default, Clone)]
+pub struct TokenPriceHistory {
+    prices: BTreeMap<u64, u64>,
+}
+
+impl TokenPriceHistory {
+    pub fn new() -> TokenPriceHistory {
+        TokenPriceHistory::default()
+    }
+
+    /// Adds a price point. Ingesting the same update twice (polling faster than
+    /// Blizzard updates the price) keeps just one point.
+    pub fn ingest(&mut self, price: TokenPrice) {
+        self.prices.insert(price.last_updated_timestamp, price.price);
+    }
+
+    /// All price points, oldest first.
+    pub fn series(&self) -> Vec<TokenPrice> {
+        self.prices.iter().map(|(&timestamp, &price)| TokenPrice {
+            last_updated_timestamp: timestamp,
+            price: price,
+        }).collect()
+    }
+}
+
+/// The freshest of several snapshots of one realm, as (last modified, listings),
+/// e.g. from overlapping runs. Listings are never merged across snapshots, since
+/// that would count anything listed in both twice. If several share the newest
+/// timestamp the first of them wins. `(0, [])` if there are no snapshots.
+pub fn latest_snapshot(snapshots: Vec<(u64, Vec<AuctionListing>)>) -> (u64, Vec<AuctionListing>) {
+    let mut latest: Option<(u64, Vec<AuctionListing>)> = None;
+    for snapshot in snapshots {
+        let newer = match latest {
+            Some(ref latest) => snapshot.0 > latest.0,
+            None => true,
+        };
+        if newer {
+            latest = Some(snapshot);
+        }
+    }
+    latest.unwrap_or((0, Vec::new()))
+}
+
+/// Total units listed per item.
+fn quantities(listings: &[AuctionListing]) -> BTreeMap<u64, u64> {
+    let mut quantities = BTreeMap::new();
+    for listing in listings {
+        *quantities.entry(listing.item).or_insert(0) += listing.quantity;
+    }
+    quantities
+}
+
+/// How the number of units listed changed per item between two snapshots, e.g.
+/// `-20` if 20 fewer were listed in `new`. Items listed in neither are left out.
+pub fn diff_snapshots(old: &[AuctionListing], new: &[AuctionListing]) -> BTreeMap<u64, i64> {
+    let old = quantities(old);
+    let mut deltas: BTreeMap<u64, i64> = quantities(new).into_iter()
+        .map(|(item, quantity)| (item, quantity as i64 - *old.get(&item).unwrap_or(&0) as i64))
+        .collect();
+    for (&item, &quantity) in &old {
+        deltas.entry(item).or_insert(-(quantity as i64));
+    }
+    deltas
+}
+
+/// Estimates how many units of each item sell over time from a series of snapshots.
+///
+/// Going from one snapshot to the next, any drop in an item's listed quantity
+/// counts as sold and increases are ignored. That's an approximation: auctions that
+/// are cancelled or expire look like sales, and sales made up for by new postings
+/// in the same interval aren't seen at all. Snapshots close together (hourly or
+/// better) keep the second effect small.
+#[derive(Debug, Default, Clone)]
+pub struct TurnoverTracker {
+    last: Option<(u64, Vec<AuctionListing>)>,
+    /// Units sold per item in the interval ending at each snapshot's timestamp.
+    sold: BTreeMap<u64, BTreeMap<u64, u64>>,
+}
+
+impl TurnoverTracker {
+    pub fn new() -> TurnoverTracker {
+        TurnoverTracker::default()
+    }
+
+    /// Adds a snapshot taken at `timestamp` (milliseconds, e.g. the dump's last
+    /// modified time). Snapshots must be ingested oldest first; one that isn't newer
+    /// than the last is ignored.
+    pub fn ingest(&mut self, timestamp: u64, listings: Vec<AuctionListing>) {
+        if let Some((last_timestamp, ref last_listings)) = self.last {
+            if timestamp <= last_timestamp {
+                return;
+            }
+            let sold: BTreeMap<u64, u64> = diff_snapshots(last_listings, &listings).into_iter()
+                .filter(|&(_, delta)| delta < 0)
+                .map(|(item, delta)| (item, (-delta) as u64))
+                .collect();
+            self.sold.insert(timestamp, sold);
+        }
+        self.last = Some((timestamp, listings));
+    }
+
+    /// Units of `item_id` sold over the `window` leading up to the latest snapshot.
+    /// An interval counts if it ended inside the window.
+    pub fn quantity_sold(&self, item_id: u64, window: Duration) -> u64 {
+        let latest = match self.last {
+            Some((timestamp, _)) => timestamp,
+            None => return 0,
+        };
+        let start = latest.saturating_sub(millis(window));
+        self.sold.iter()
+            .filter(|&(&timestamp, _)| timestamp > start)
+            .fold(0, |total, (_, sold)| total + sold.get(&item_id).cloned().unwrap_or(0))
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use battle_net_api_client::{AuctionListing, TokenPrice};
+
+    use super::*;
+
+    fn listing(item: u64, buyout: u64, quantity: u64) -> AuctionListing {
+        AuctionListing { item: item, buyout: buyout, quantity: quantity, ..Default::default() }
+    }
+
+    #[test]
+    fn test_price_extremes() {
+        let mut extremes = PriceExtremes::new();
+        extremes.observe(&[listing(1, 500, 5), listing(1, 0, 1), listing(2, 10, 1)]);
+        extremes.observe(&[listing(1, 300, 1), listing(1, 60, 1)]);
+        assert_eq!(extremes.range(1), Some((60, 300)));
+        assert_eq!(extremes.range(2), Some((10, 10)));
+        assert_eq!(extremes.range(3), None);
+    }
+
+    #[test]
+    fn test_token_price_history() {
+        let price = |timestamp, price| TokenPrice { last_updated_timestamp: timestamp, price: price };
+        let mut history = TokenPriceHistory::new();
+        history.ingest(price(2000, 1500000000));
+        history.ingest(price(1000, 1400000000));
+        history.ingest(price(2000, 1500000000));
+        assert_eq!(history.series(), vec![price(1000, 1400000000), price(2000, 1500000000)]);
+    }
+
+    #[test]
+    fn test_diff_snapshots() {
+        let deltas = diff_snapshots(&[listing(1, 10, 5), listing(1, 10, 5), listing(2, 10, 1)],
+                                    &[listing(1, 10, 4), listing(3, 10, 2)]);
+        let expected: BTreeMap<u64, i64> = vec![(1, -6), (2, -1), (3, 2)].into_iter().collect();
+        assert_eq!(deltas, expected);
+    }
+
+    #[test]
+    fn test_turnover_tracker() {
+        let hour = 60 * 60 * 1000;
+        let mut tracker = TurnoverTracker::new();
+        tracker.ingest(0, vec![listing(1, 10, 20)]);
+        tracker.ingest(hour, vec![listing(1, 10, 15)]);
+        tracker.ingest(2 * hour, vec![listing(1, 10, 30)]);
+        tracker.ingest(3 * hour, vec![listing(1, 10, 22)]);
+        tracker.ingest(3 * hour, vec![]);
+        assert_eq!(tracker.quantity_sold(1, Duration::from_secs(24 * 60 * 60)), 13);
+        assert_eq!(tracker.quantity_sold(1, Duration::from_secs(60 * 60)), 8);
+        assert_eq!(tracker.quantity_sold(2, Duration::from_secs(24 * 60 * 60)), 0);
+    }
+
+    #[test]
+    fn test_rolling_median() {
+        let mut history = PriceHistory::new(0.5, 3);
+        for (timestamp, &price) in [100, 1, 110, 120, 5000].iter().enumerate() {
+            history.observe(timestamp as u64, &[listing(1, price, 1)]);
+        }
+        // Only the last 3 prices are kept.
+        assert_eq!(history.rolling_median(1, 10), Some(120));
+        assert_eq!(history.rolling_median(1, 2), Some(2560));
+        assert_eq!(history.rolling_median(1, 0), None);
+        assert_eq!(history.rolling_median(2, 3), None);
+        assert!(history.ema(1).unwrap() > 2000);
+    }
+
+    #[test]
+    fn test_value_band() {
+        let mut history = PriceHistory::new(0.5, 10);
+        for (timestamp, &price) in [100, 110, 90, 105].iter().enumerate() {
+            history.observe(timestamp as u64, &[listing(1, price, 1)]);
+        }
+        assert_eq!(history.value_band(1), None);
+        history.observe(4, &[listing(1, 95, 1)]);
+        // Sorted: 90, 95, 100, 105, 110. Quartiles 95 and 105, margin 15.
+        assert_eq!(history.value_band(1), Some((80, 120)));
+        assert_eq!(history.value_band(2), None);
+    }
+
+    #[test]
+    fn test_latest_snapshot() {
+        let snapshots = vec![(10, vec![listing(1, 5, 1), listing(2, 5, 1)]), (30, vec![listing(1, 5, 2)]),
+                             (20, vec![listing(3, 5, 1)]), (30, vec![listing(4, 5, 1)])];
+        let (last_modified, listings) = latest_snapshot(snapshots);
+        assert_eq!(last_modified, 30);
+        assert_eq!(listings.iter().map(|listing| (listing.item, listing.quantity)).collect::<Vec<_>>(), vec![(1, 2)]);
+        assert_eq!(latest_snapshot(Vec::new()).0, 0);
+    }
+
+    #[test]
+    fn test_retention() {
+        const HOUR: u64 = 3600 * 1000;
+        let retention = Retention { bucket: Duration::from_secs(24 * 3600), max_age: Duration::from_secs(2 * 24 * 3600) };
+        let mut history = PriceHistory::new(0.5, 2).retention(retention);
+        // Hourly snapshots over four days, the price being the day number.
+        for hour in 0..96 {
+            history.observe(hour * HOUR, &[listing(1, hour / 24 + 1, 1)]);
+        }
+        assert_eq!(history.rolling_median(1, 2), Some(4));
+        assert_eq!(history.downsampled(1), vec![(0, 1), (24 * HOUR, 2), (48 * HOUR, 3), (72 * HOUR, 4)]);
+        history.prune();
+        assert_eq!(history.downsampled(1), vec![(48 * HOUR, 3), (72 * HOUR, 4)]);
+        assert!(PriceHistory::new(0.5, 2).downsampled(1).is_empty());
+    }
+}
@@ -0,0 +1,109 @@
+//! Pure helpers over realm lists returned by `get_realms`.
+use std::collections::BTreeMap;
+
+use battle_net_api_client::RealmInfo;
+
+/// How a realm list changed between two fetches.
+#[derive(Debug, Default)]
+pub struct RealmDiff {
+    /// Realms in the new list that weren't in the old one.
+    pub added: Vec<RealmInfo>,
+    /// Realms in the old list that aren't in the new one.
+    pub removed: Vec<RealmInfo>,
+    /// Realms in both lists whose connected realms changed, as `(old, new)`.
+    pub regrouped: Vec<(RealmInfo, RealmInfo)>,
+}
+
+impl RealmDiff {
+    /// Whether the two lists describe the same realms and connections.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.regrouped.is_empty()
+    }
+}
+
+/// Compares two realm lists by slug. Connected realm membership is compared as a
+/// set, so Blizzard reordering a group's slugs doesn't count as a change.
+pub fn diff_realms(old: &[RealmInfo], new: &[RealmInfo]) -> RealmDiff {
+    let old_by_slug: BTreeMap<&str, &RealmInfo> = old.iter().map(|realm| (&realm.slug[..], realm)).collect();
+    let new_by_slug: BTreeMap<&str, &RealmInfo> = new.iter().map(|realm| (&realm.slug[..], realm)).collect();
+    let mut diff = RealmDiff::default();
+    for (slug, &new_realm) in &new_by_slug {
+        match old_by_slug.get(slug) {
+            Some(&old_realm) => {
+                if sorted(&old_realm.connected_realms) != sorted(&new_realm.connected_realms) {
+                    diff.regrouped.push((old_realm.clone(), new_realm.clone()));
+                }
+            },
+            None => diff.added.push(new_realm.clone()),
+        }
+    }
+    for (slug, &old_realm) in &old_by_slug {
+        if !new_by_slug.contains_key(slug) {
+            diff.removed.push(old_realm.clone());
+        }
+    }
+    diff
+}
+
+/// Like `BattleNetApiClient::process_connected_realms`, but fully deterministic:
+/// each group's slugs are sorted, and the groups are sorted and deduplicated, so the
+/// output (and anything keyed by a group's first slug) is stable across runs even
+/// when Blizzard shuffles the arrays.
+pub fn canonical_realm_groups(realm_infos: &[RealmInfo]) -> Vec<Vec<String>> {
+    let mut groups: Vec<Vec<String>> = realm_infos.iter().map(|realm| {
+        let mut group = if realm.connected_realms.is_empty() {
+            vec![realm.slug.clone()]
+        } else {
+            realm.connected_realms.clone()
+        };
+        group.sort();
+        group.dedup();
+        group
+    }).collect();
+    groups.sort();
+    groups.dedup();
+    groups
+}
+
+fn sorted(slugs: &[String]) -> Vec<&str> {
+    let mut slugs: Vec<&str> = slugs.iter().map(|slug| &slug[..]).collect();
+    slugs.sort();
+    slugs
+}
+
+#[cfg(test)]
+mod tests {
+    use battle_net_api_client::RealmInfo;
+
+    use super::*;
+
+    fn realm(slug: &str, connected: &[&str]) -> RealmInfo {
+        RealmInfo {
+            name: slug.to_owned(),
+            slug: slug.to_owned(),
+            connected_realms: connected.iter().map(|s| s.to_string()).collect(),
+            population: None,
+        }
+    }
+
+    #[test]
+    fn test_diff_realms() {
+        let old = vec![realm("a", &["a", "b"]), realm("b", &["a", "b"]), realm("c", &["c"])];
+        let new = vec![realm("a", &["b", "a"]), realm("b", &["b", "a", "d"]), realm("d", &["b", "a", "d"])];
+        let diff = diff_realms(&old, &new);
+        assert_eq!(diff.added.iter().map(|r| &r.slug[..]).collect::<Vec<&str>>(), vec!["d"]);
+        assert_eq!(diff.removed.iter().map(|r| &r.slug[..]).collect::<Vec<&str>>(), vec!["c"]);
+        assert_eq!(diff.regrouped.iter().map(|&(ref o, _)| &o.slug[..]).collect::<Vec<&str>>(), vec!["b"]);
+        assert!(diff_realms(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn test_canonical_realm_groups() {
+        let realms = vec![realm("c", &["c"]), realm("b", &["b", "a"]), realm("a", &["a", "b"])];
+        let expected = vec![vec!["a".to_owned(), "b".to_owned()], vec!["c".to_owned()]];
+        assert_eq!(canonical_realm_groups(&realms), expected);
+        let mut shuffled = realms.clone();
+        shuffled.reverse();
+        assert_eq!(canonical_realm_groups(&shuffled), expected);
+    }
+}
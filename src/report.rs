@@ -0,0 +1,71 @@
+//! Plain text output for people reading results in a terminal.
+use std::cmp;
+use std::iter;
+
+use battle_net_api_client::ItemInfo;
+
+/// Formats copper as gold, silver and copper the same way the web page does,
+/// e.g. `12g3s45c`.
+pub fn format_money(copper: u64) -> String {
+    format!("{}g{}s{}c", copper / 10_000, copper / 100 % 100, copper % 100)
+}
+
+/// Formats rows of items and prices (in copper) as an aligned table, e.g.
+///
+/// ```text
+/// Item              | Id     | Min buyout
+/// ------------------+--------+-----------
+/// Blood of Sargeras | 124124 |    20g0s0c
+/// ```
+///
+/// `metric_label` heads the price column. Rows are printed in the order given.
+pub fn format_price_table(rows: &[(ItemInfo, u64)], metric_label: &str) -> String {
+    let prices: Vec<String> = rows.iter().map(|&(_, price)| format_money(price)).collect();
+    let ids: Vec<String> = rows.iter().map(|&(ref item, _)| item.id.to_string()).collect();
+    let name_width = rows.iter().fold(4, |width, &(ref item, _)| cmp::max(width, item.name.chars().count()));
+    let id_width = ids.iter().fold(2, |width, id| cmp::max(width, id.len()));
+    let price_width = prices.iter().fold(metric_label.chars().count(), |width, price| cmp::max(width, price.len()));
+
+    let mut table = format!("{:<name$} | {:<id$} | {}\n", "Item", "Id", metric_label, name = name_width, id = id_width);
+    table.push_str(&format!("{}-+-{}-+-{}\n", dashes(name_width), dashes(id_width), dashes(price_width)));
+    for ((&(ref item, _), id), price) in rows.iter().zip(ids.iter()).zip(prices.iter()) {
+        table.push_str(&format!("{:<name$} | {:>id$} | {:>price$}\n", item.name, id, price,
+                                name = name_width, id = id_width, price = price_width));
+    }
+    table
+}
+
+fn dashes(width: usize) -> String {
+    iter::repeat('-').take(width).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use battle_net_api_client::ItemInfo;
+
+    use super::*;
+
+    #[test]
+    fn test_format_money() {
+        assert_eq!(format_money(123456), "12g34s56c");
+        assert_eq!(format_money(99), "0g0s99c");
+    }
+
+    #[test]
+    fn test_format_price_table() {
+        let item = |id: u64, name: &str| ItemInfo {
+            id: id,
+            name: name.to_owned(),
+            icon: String::new(),
+            purchase_price: None,
+            item_class: None,
+            item_subclass: None,
+        };
+        let rows = vec![(item(124124, "Blood of Sargeras"), 200000), (item(1, "Ore"), 5)];
+        assert_eq!(format_price_table(&rows, "Min buyout"),
+                   "Item              | Id     | Min buyout\n\
+                    ------------------+--------+-----------\n\
+                    Blood of Sargeras | 124124 |    20g0s0c\n\
+                    Ore               |      1 |     0g0s5c\n");
+    }
+}
@@ -0,0 +1,371 @@
+//! Fetching auction data for many connected realm groups at once, in a way
+//! that can pick up where it left off if the process dies partway through.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use scoped_threadpool::Pool;
+use serde_json;
+
+use battle_net_api_client::{AuctionListing, BattleNetApiClient, BattleNetApiError};
+
+/// The key a connected realm group is stored under: its first slug.
+pub fn group_key(group: &[String]) -> String {
+    group.get(0).cloned().unwrap_or(String::new())
+}
+
+/// Remembers the newest `lastModified` fetched for each connected realm group.
+/// If opened with a path, every update is written straight back to disk so a
+/// crashed scrape loses nothing it already fetched.
+#[derive(Debug)]
+pub struct CutoffStore {
+    path: Option<PathBuf>,
+    cutoffs: BTreeMap<String, u64>,
+}
+
+impl CutoffStore {
+    /// An empty store that only lives in memory.
+    pub fn in_memory() -> CutoffStore {
+        CutoffStore {
+            path: None,
+            cutoffs: BTreeMap::new(),
+        }
+    }
+
+    /// Opens a store saved at `path`, starting empty if there's no file there yet.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<CutoffStore> {
+        let path = path.as_ref().to_path_buf();
+        let cutoffs = match File::open(&path) {
+            Ok(file) => serde_json::from_reader(file)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(CutoffStore {
+            path: Some(path),
+            cutoffs: cutoffs,
+        })
+    }
+
+    /// The cutoff to pass to `get_auction_listings` for a group. 0 if we've never fetched it.
+    pub fn get(&self, key: &str) -> u64 {
+        *self.cutoffs.get(key).unwrap_or(&0)
+    }
+
+    /// Records that we fetched a group's data as of `last_modified`, saving the store
+    /// if it has a path. The store is written to a `.tmp` file next to it and then
+    /// renamed over it, so a crash mid-write leaves the old one intact.
+    pub fn record(&mut self, key: &str, last_modified: u64) -> io::Result<()> {
+        self.cutoffs.insert(key.to_owned(), last_modified);
+        match self.path {
+            Some(ref path) => {
+                let mut tmp_path = path.clone().into_os_string();
+                tmp_path.push(".tmp");
+                {
+                    let mut file = File::create(&tmp_path)?;
+                    serde_json::to_writer(&mut file, &self.cutoffs)
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    file.sync_all()?;
+                }
+                fs::rename(&tmp_path, path)
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+/// What happened when fetching one connected realm group.
+#[derive(Debug)]
+pub enum RealmGroupResult {
+    /// New data was downloaded.
+    Fetched { key: String, last_modified: u64, listings: Vec<AuctionListing> },
+    /// Nothing new since the stored cutoff, or the group was already completed.
+    Skipped { key: String },
+    /// The download failed.
+    Failed { key: String, error: BattleNetApiError },
+}
+
+/// Fetches auction listings for each of `groups` (as returned by
+/// `process_connected_realms`) using `threads` threads.
+///
+/// To resume an interrupted scrape, pass the same `cutoffs` store and the keys
+/// (see `group_key`) of the groups that were already handled as `completed`. Completed
+/// groups are skipped without making any requests, and every other group is only
+/// downloaded if its `lastModified` has advanced past its stored cutoff. Even with an
+/// empty `completed` set, a store opened from disk means a restart only downloads the
+/// groups that still have new data.
+pub fn fetch_groups(client: &BattleNetApiClient, groups: &[Vec<String>], cutoffs: &Mutex<CutoffStore>,
+                    completed: &BTreeSet<String>, threads: u32) -> Vec<RealmGroupResult> {
+    let results = Mutex::new(Vec::new());
+    let mut pool = Pool::new(threads);
+    pool.scoped(|scope| {
+        for group in groups {
+            let results = &results;
+            scope.execute(move || {
+                let key = group_key(group);
+                if completed.contains(&key) {
+                    results.lock().unwrap().push(RealmGroupResult::Skipped { key: key });
+                    return;
+                }
+                let cutoff = cutoffs.lock().unwrap().get(&key);
+                let result = match client.get_auction_listings(&key, cutoff) {
+                    Ok(Some((last_modified, listings))) => {
+                        if let Err(e) = cutoffs.lock().unwrap().record(&key, last_modified) {
+                            if !client.is_quiet() {
+                                println!("Failed to save cutoff for {}: {}", key, e);
+                            }
+                        }
+                        RealmGroupResult::Fetched { key: key, last_modified: last_modified, listings: listings }
+                    },
+                    Ok(None) => RealmGroupResult::Skipped { key: key },
+                    Err(e) => RealmGroupResult::Failed { key: key, error: e },
+                };
+                results.lock().unwrap().push(result);
+            });
+        }
+        scope.join_all();
+    });
+    results.into_inner().unwrap()
+}
+
+/// Which of the expected connected realm groups a scrape actually covered, by key
+/// (see `group_key`). Every expected group is in exactly one list.
+#[derive(Debug, Default, PartialEq)]
+pub struct CoverageReport {
+    /// Groups that returned new data.
+    pub fetched: Vec<String>,
+    /// Groups with nothing new since their cutoff.
+    pub skipped: Vec<String>,
+    /// Groups that failed, or have no result at all.
+    pub missing: Vec<String>,
+}
+
+impl CoverageReport {
+    /// Whether every expected group was either fetched or skipped.
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
+    }
+}
+
+/// Checks the results of `fetch_groups` against the groups that were expected
+/// (usually `process_connected_realms` output), so a scrape that silently lost a
+/// chunk of realms gets noticed. Results for groups that weren't expected are ignored.
+pub fn coverage_report(expected_groups: &[Vec<String>], fetched: &[RealmGroupResult]) -> CoverageReport {
+    let mut outcomes = BTreeMap::new();
+    for result in fetched {
+        match *result {
+            RealmGroupResult::Fetched { ref key, .. } => outcomes.insert(&key[..], true),
+            RealmGroupResult::Skipped { ref key } => outcomes.insert(&key[..], false),
+            RealmGroupResult::Failed { .. } => None,
+        };
+    }
+    let mut report = CoverageReport::default();
+    for group in expected_groups {
+        let key = group_key(group);
+        match outcomes.get(&key[..]) {
+            Some(&true) => report.fetched.push(key),
+            Some(&false) => report.skipped.push(key),
+            None => report.missing.push(key),
+        }
+    }
+    report
+}
+
+/// Somewhere a `ScrapeJob` puts the listings it fetches, e.g. a database.
+pub trait ListingSink: Send + Sync {
+    /// Stores a group's listings, returning how many rows were written.
+    fn store(&self, key: &str, last_modified: u64, listings: &[AuctionListing]) -> io::Result<usize>;
+}
+
+/// What a `ScrapeJob` run did.
+#[derive(Debug, Default)]
+pub struct ScrapeReport {
+    /// Keys (see `group_key`) of the groups with new data.
+    pub fetched: Vec<String>,
+    /// Keys of the groups with nothing new since their cutoff.
+    pub skipped: Vec<String>,
+    /// Keys of the groups that failed to download or store, with why.
+    pub failed: Vec<(String, String)>,
+    /// Rows the sink reported writing.
+    pub rows_stored: usize,
+    pub elapsed: Duration,
+}
+
+/// The usual end-to-end scrape: fetch the realm list, work out the connected realm
+/// groups, download every group with new data past its cutoff and hand the listings
+/// to a sink. The region is the client's.
+///
+/// Cutoffs advance as soon as a group is downloaded, so a group whose listings the
+/// sink fails to store isn't downloaded again next run. It's reported in `failed`.
+pub struct ScrapeJob<'a> {
+    client: &'a BattleNetApiClient,
+    cutoffs: Mutex<CutoffStore>,
+    sink: Option<Box<ListingSink + 'a>>,
+    threads: u32,
+}
+
+impl<'a> ScrapeJob<'a> {
+    /// A job fetching with `client` against the cutoffs in `cutoffs`. By default it
+    /// uses 5 threads and stores nothing.
+    pub fn new(client: &'a BattleNetApiClient, cutoffs: CutoffStore) -> ScrapeJob<'a> {
+        ScrapeJob {
+            client: client,
+            cutoffs: Mutex::new(cutoffs),
+            sink: None,
+            threads: 5,
+        }
+    }
+
+    /// Sets where fetched listings are stored.
+    pub fn sink(mut self, sink: Box<ListingSink + 'a>) -> ScrapeJob<'a> {
+        self.sink = Some(sink);
+        self
+    }
+
+    /// Sets how many groups are fetched at once.
+    pub fn threads(mut self, threads: u32) -> ScrapeJob<'a> {
+        self.threads = threads;
+        self
+    }
+
+    /// Runs the scrape. Fails only if the realm list can't be fetched; failures of
+    /// individual groups are reported instead.
+    pub fn run(&self) -> Result<ScrapeReport, BattleNetApiError> {
+        let start = Instant::now();
+        let realms = self.client.get_realms()?;
+        let groups = BattleNetApiClient::process_connected_realms(&realms);
+        let mut report = ScrapeReport::default();
+        for result in fetch_groups(self.client, &groups, &self.cutoffs, &BTreeSet::new(), self.threads) {
+            match result {
+                RealmGroupResult::Fetched { key, last_modified, listings } => {
+                    let stored = match self.sink {
+                        Some(ref sink) => sink.store(&key, last_modified, &listings),
+                        None => Ok(0),
+                    };
+                    match stored {
+                        Ok(rows) => {
+                            report.rows_stored += rows;
+                            report.fetched.push(key);
+                        },
+                        Err(e) => report.failed.push((key, e.to_string())),
+                    }
+                },
+                RealmGroupResult::Skipped { key } => report.skipped.push(key),
+                RealmGroupResult::Failed { key, error } => report.failed.push((key, error.to_string())),
+            }
+        }
+        report.fetched.sort();
+        report.skipped.sort();
+        report.failed.sort();
+        report.elapsed = start.elapsed();
+        Ok(report)
+    }
+
+    /// The cutoffs as of the end of the last run.
+    pub fn into_cutoffs(self) -> CutoffStore {
+        self.cutoffs.into_inner().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::sync::Mutex;
+
+    use hyper::header::Headers;
+    use hyper::status::StatusCode;
+
+    use battle_net_api_client::{AuctionListing, BattleNetApiClient, BattleNetApiError, HttpResponse, RetryConfig,
+                                Transport};
+
+    use super::*;
+
+    /// Serves two single-realm groups: `a`, which has new data, and `b`, whose data
+    /// file errors.
+    struct TwoRealms;
+
+    impl Transport for TwoRealms {
+        fn get(&self, url: &str, _: Headers) -> Result<HttpResponse, BattleNetApiError> {
+            let (status, body) = if url.contains("realm/status") {
+                (StatusCode::Ok, "{\"realms\":[{\"name\":\"A\",\"slug\":\"a\",\"connected_realms\":[\"a\"]},\
+                                  {\"name\":\"B\",\"slug\":\"b\",\"connected_realms\":[\"b\"]}]}".to_owned())
+            } else if url.contains("auction/data/") {
+                let slug = url.split("auction/data/").nth(1).unwrap().split('?').next().unwrap();
+                (StatusCode::Ok, format!("{{\"files\":[{{\"url\":\"https://data/{}.json\",\"lastModified\":10}}]}}", slug))
+            } else if url.contains("data/a.json") {
+                (StatusCode::Ok, "{\"auctions\":[{\"item\":1,\"buyout\":5,\"quantity\":1},\
+                                                 {\"item\":2,\"buyout\":5,\"quantity\":1}]}".to_owned())
+            } else {
+                (StatusCode::InternalServerError, String::new())
+            };
+            Ok(HttpResponse { status: status, headers: Headers::new(), body: body.into_bytes() })
+        }
+    }
+
+    struct CountingSink {
+        stored: Mutex<Vec<String>>,
+    }
+
+    impl ListingSink for CountingSink {
+        fn store(&self, key: &str, _: u64, listings: &[AuctionListing]) -> io::Result<usize> {
+            self.stored.lock().unwrap().push(key.to_owned());
+            Ok(listings.len())
+        }
+    }
+
+    #[test]
+    fn test_cutoff_store_persists() {
+        let path = env::temp_dir().join("blood-money-test-cutoffs.json");
+        let _ = fs::remove_file(&path);
+        {
+            let mut store = CutoffStore::open(&path).unwrap();
+            assert_eq!(store.get("earthen-ring"), 0);
+            store.record("earthen-ring", 1480000000000).unwrap();
+        }
+        let store = CutoffStore::open(&path).unwrap();
+        assert_eq!(store.get("earthen-ring"), 1480000000000);
+        assert!(!env::temp_dir().join("blood-money-test-cutoffs.json.tmp").exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_scrape_job() {
+        let client = BattleNetApiClient::builder("token")
+            .transport(Box::new(TwoRealms))
+            .retry_config(RetryConfig { max_retries: 0, ..Default::default() })
+            .build();
+        let sink = CountingSink { stored: Mutex::new(Vec::new()) };
+        let job = ScrapeJob::new(&client, CutoffStore::in_memory()).sink(Box::new(sink)).threads(2);
+        let report = job.run().unwrap();
+        assert_eq!(report.fetched, vec!["a".to_owned()]);
+        assert_eq!(report.failed.iter().map(|&(ref key, _)| &key[..]).collect::<Vec<&str>>(), vec!["b"]);
+        assert_eq!(report.rows_stored, 2);
+        let report = job.run().unwrap();
+        assert_eq!(report.skipped, vec!["a".to_owned()]);
+        assert_eq!(job.into_cutoffs().get("a"), 10);
+    }
+
+    #[test]
+    fn test_coverage_report() {
+        let groups: Vec<Vec<String>> = vec![vec!["a".to_owned(), "a2".to_owned()], vec!["b".to_owned()],
+                                            vec!["c".to_owned()], vec!["d".to_owned()]];
+        let results = vec![
+            RealmGroupResult::Fetched { key: "a".to_owned(), last_modified: 1, listings: Vec::new() },
+            RealmGroupResult::Skipped { key: "b".to_owned() },
+            RealmGroupResult::Failed { key: "c".to_owned(), error: BattleNetApiError::TimedOut },
+            RealmGroupResult::Skipped { key: "z".to_owned() },
+        ];
+        let report = coverage_report(&groups, &results);
+        assert_eq!(report, CoverageReport {
+            fetched: vec!["a".to_owned()],
+            skipped: vec!["b".to_owned()],
+            missing: vec!["c".to_owned(), "d".to_owned()],
+        });
+        assert!(!report.is_complete());
+    }
+}
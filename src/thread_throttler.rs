@@ -1,7 +1,21 @@
+use std::cmp;
 use std::collections::VecDeque;
-use std::sync::{Condvar, Mutex};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
+use clock::{Clock, SystemClock};
+
+/// How a ThreadThrottler spreads out the threads it lets through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThrottleMode {
+    /// Never let more than `rate` threads through in any `interval`
+    /// long window. Up to `rate` threads can get through at once.
+    SlidingWindow,
+    /// Let threads through evenly spaced, one every `interval / rate`.
+    /// Smoother on the server at the cost of never bursting.
+    Smoothed,
+}
+
 /// A ThreadThrottle is used to control the rate of
 /// thread progress, for example to limit the number
 /// of requests sent to a web API per second.
@@ -10,12 +24,16 @@ use std::time::{Duration, Instant};
 /// will be slept until some time has passed.
 /// Note that the throttle will not necessarily let work
 /// through in FIFO order.
+/// Time is kept, and waited out, on a `Clock`, so under a
+/// `ManualClock` the waits just move the clock along.
 pub struct ThreadThrottler {
     rate: u32,
     interval: Duration,
+    mode: ThrottleMode,
+    clock: Arc<Clock>,
 
     action_history: Mutex<VecDeque<Instant>>,
-    cv: Condvar,
+    next_slot: Mutex<Instant>,
 }
 
 impl ThreadThrottler {
@@ -23,17 +41,29 @@ impl ThreadThrottler {
     /// specified number of calls pass within the provided
     /// interval.
     pub fn new(rate: u32, interval: Duration) -> ThreadThrottler {
+        ThreadThrottler::with_mode(rate, interval, ThrottleMode::SlidingWindow)
+    }
+
+    /// Like `new` but lets threads through according to `mode`.
+    pub fn with_mode(rate: u32, interval: Duration, mode: ThrottleMode) -> ThreadThrottler {
+        ThreadThrottler::with_clock(rate, interval, mode, Arc::new(SystemClock))
+    }
+
+    /// Like `with_mode` but tells time by `clock`.
+    pub fn with_clock(rate: u32, interval: Duration, mode: ThrottleMode, clock: Arc<Clock>) -> ThreadThrottler {
         assert!(rate > 0, "Rate must be positive.");
         assert!(interval > Duration::new(0, 0), "Duration must be non-zero.");
 
-        let mut tt = ThreadThrottler {
+        let now = clock.now();
+        ThreadThrottler {
             rate: rate,
             interval: interval,
+            mode: mode,
+            clock: clock,
 
             action_history: Mutex::new(VecDeque::new()),
-            cv: Condvar::new(),
-        };
-        tt
+            next_slot: Mutex::new(now),
+        }
     }
 
     /// Attempts to pass through the throttle. If there is
@@ -41,28 +71,54 @@ impl ThreadThrottler {
     /// Otherwise, the calling thread will block for some
     /// time before trying to pass through again.
     pub fn pass_through_or_block(&self) {
+        match self.mode {
+            ThrottleMode::SlidingWindow => self.pass_through_sliding_window(),
+            ThrottleMode::Smoothed => self.pass_through_smoothed(),
+        }
+    }
+
+    fn pass_through_sliding_window(&self) {
         let mut history = self.action_history.lock().unwrap();
-        prune_history(&mut history, (Instant::now() - self.interval));
+        prune_history(&mut history, self.clock.now() - self.interval);
 
         while history.len() >= self.rate as usize {
-            let minimum_sleep = (*history.get(0).unwrap() + self.interval) - Instant::now();
-            let (lock_result, _) = self.cv.wait_timeout(history, minimum_sleep).unwrap();
-            history = lock_result;
-            prune_history(&mut history, (Instant::now() - self.interval));
+            let minimum_sleep = (*history.get(0).unwrap() + self.interval) - self.clock.now();
+            // Let other threads at the history while this one waits.
+            drop(history);
+            self.clock.sleep(minimum_sleep);
+            history = self.action_history.lock().unwrap();
+            prune_history(&mut history, self.clock.now() - self.interval);
         }
 
-        history.push_back(Instant::now());
+        history.push_back(self.clock.now());
+    }
+
+    fn pass_through_smoothed(&self) {
+        // Reserve the next free slot, then sleep until it comes up.
+        // Later threads reserve later slots, so this one is FIFO.
+        let now = self.clock.now();
+        let slot = {
+            let mut next_slot = self.next_slot.lock().unwrap();
+            let slot = cmp::max(now, *next_slot);
+            *next_slot = slot + self.interval / self.rate;
+            slot
+        };
+        if slot > now {
+            self.clock.sleep(slot - now);
+        }
     }
 }
 
 /// Prunes a sorted history of events, cutting off those
-/// older than a cutoff.
+/// at or before a cutoff. An event exactly an interval ago
+/// has left the window, so a wait that ends right on it
+/// (as sleeping on a `ManualClock` does) gets through.
 fn prune_history(history: &mut VecDeque<Instant>, cutoff: Instant) {
     if history.is_empty() {
         return;
     }
 
-    while !history.is_empty() && *history.front().unwrap() < cutoff {
+    while !history.is_empty() && *history.front().unwrap() <= cutoff {
         history.pop_front();
     }
 }
@@ -70,8 +126,11 @@ fn prune_history(history: &mut VecDeque<Instant>, cutoff: Instant) {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
     use std::time::{Duration, Instant};
 
+    use clock::ManualClock;
+
     use super::*;
 
     #[test]
@@ -91,4 +150,33 @@ mod tests {
         let run_time = Instant::now() - start_time;
         assert!(run_time > Duration::new(1, 0));
     }
+
+    #[test]
+    fn test_smoothed_throttle() {
+        // 10 threads every 100 milliseconds, so one every 10 milliseconds.
+        let tt = ThreadThrottler::with_mode(10, Duration::new(0, 100_000_000), ThrottleMode::Smoothed);
+        let start_time = Instant::now();
+        for _ in 0..3 {
+            tt.pass_through_or_block();
+        }
+        // Unlike the sliding window these don't all get through at once.
+        assert!(Instant::now() - start_time >= Duration::new(0, 20_000_000));
+    }
+
+    #[test]
+    fn test_manual_clock_throttle() {
+        let clock = Arc::new(ManualClock::new());
+        let tt = ThreadThrottler::with_clock(1, Duration::new(0, 100_000_000), ThrottleMode::SlidingWindow, clock.clone());
+        for _ in 0..11 {
+            tt.pass_through_or_block();
+        }
+        assert_eq!(clock.elapsed(), Duration::new(1, 0));
+
+        let clock = Arc::new(ManualClock::new());
+        let tt = ThreadThrottler::with_clock(10, Duration::new(0, 100_000_000), ThrottleMode::Smoothed, clock.clone());
+        for _ in 0..3 {
+            tt.pass_through_or_block();
+        }
+        assert_eq!(clock.elapsed(), Duration::new(0, 20_000_000));
+    }
 }
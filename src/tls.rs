@@ -0,0 +1,134 @@
+//! Talking to Blizzard through a custom TLS setup, e.g. a corporate CA bundle or
+//! a pinned certificate. Needs the `tls` feature. Clients built without it use
+//! hyper's default client as before.
+//!
+//! ```ignore
+//! let transport = tls_transport(TlsConfig::RootCertificate(PathBuf::from("/etc/ssl/corp-ca.der")))?;
+//! let client = BattleNetApiClient::builder(&token).transport(Box::new(transport)).build();
+//! ```
+//!
+//! The token request and `MultiRegionClient` take hyper clients instead, so they
+//! can use the same setup:
+//!
+//! ```ignore
+//! let make_client = tls_client_factory(TlsConfig::RootCertificate(PathBuf::from("/etc/ssl/corp-ca.der")))?;
+//! let multi = MultiRegionClient::from_credentials_with_clients(&id, &secret, &regions, &config, &*make_client)?;
+//! ```
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+use hyper::client::Client;
+use hyper::net::HttpsConnector;
+use hyper_native_tls::NativeTlsClient;
+use native_tls::{self, Certificate, TlsConnector};
+
+use battle_net_api_client::HyperTransport;
+use oauth::ClientFactory;
+
+/// How to set up TLS.
+pub enum TlsConfig {
+    /// Trust the DER encoded CA certificate at this path on top of the system's
+    /// trust store.
+    RootCertificate(PathBuf),
+    /// Use a fully configured connector, for anything `RootCertificate` can't do.
+    Connector(TlsConnector),
+}
+
+/// Why a TLS transport couldn't be set up.
+#[derive(Debug)]
+pub enum TlsSetupError {
+    /// The certificate file couldn't be read.
+    Io(io::Error),
+    /// The TLS library rejected the configuration or certificate.
+    Tls(native_tls::Error),
+}
+
+impl fmt::Display for TlsSetupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TlsSetupError::Io(ref e) => write!(f, "failed to read certificate: {}", e),
+            TlsSetupError::Tls(ref e) => write!(f, "failed to set up TLS: {}", e),
+        }
+    }
+}
+
+impl Error for TlsSetupError {
+    fn description(&self) -> &str {
+        match *self {
+            TlsSetupError::Io(_) => "failed to read certificate",
+            TlsSetupError::Tls(_) => "failed to set up TLS",
+        }
+    }
+}
+
+impl From<io::Error> for TlsSetupError {
+    fn from(e: io::Error) -> TlsSetupError {
+        TlsSetupError::Io(e)
+    }
+}
+
+impl From<native_tls::Error> for TlsSetupError {
+    fn from(e: native_tls::Error) -> TlsSetupError {
+        TlsSetupError::Tls(e)
+    }
+}
+
+/// Builds a `HyperTransport` whose HTTPS connections are set up according to `config`.
+pub fn tls_transport(config: TlsConfig) -> Result<HyperTransport, TlsSetupError> {
+    Ok(HyperTransport::with_client(tls_client(config)?))
+}
+
+/// Builds a hyper client whose HTTPS connections are set up according to `config`,
+/// e.g. for `oauth::fetch_access_token_with_client`.
+pub fn tls_client(config: TlsConfig) -> Result<Client, TlsSetupError> {
+    Ok(client_with(native_tls_client(config)?))
+}
+
+/// Like `tls_client` but returns a factory making as many such clients as needed,
+/// for `MultiRegionClient::from_credentials_with_clients`. The certificate is only
+/// read once.
+pub fn tls_client_factory(config: TlsConfig) -> Result<Box<ClientFactory>, TlsSetupError> {
+    let tls = native_tls_client(config)?;
+    Ok(Box::new(move || client_with(tls.clone())))
+}
+
+fn client_with(tls: NativeTlsClient) -> Client {
+    Client::with_connector(HttpsConnector::new(tls))
+}
+
+fn native_tls_client(config: TlsConfig) -> Result<NativeTlsClient, TlsSetupError> {
+    let connector = match config {
+        TlsConfig::RootCertificate(path) => {
+            let mut der = Vec::new();
+            File::open(&path)?.read_to_end(&mut der)?;
+            let mut builder = TlsConnector::builder()?;
+            builder.add_root_certificate(Certificate::from_der(&der)?)?;
+            builder.build()?
+        },
+        TlsConfig::Connector(connector) => connector,
+    };
+    Ok(NativeTlsClient::from(connector))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn test_missing_certificate() {
+        match tls_transport(TlsConfig::RootCertificate(PathBuf::from("/nonexistent/ca.der"))) {
+            Err(TlsSetupError::Io(_)) => (),
+            Err(e) => panic!("Expected an io error, got {}", e),
+            Ok(_) => panic!("Expected an io error"),
+        }
+        match tls_client_factory(TlsConfig::RootCertificate(PathBuf::from("/nonexistent/ca.der"))) {
+            Err(TlsSetupError::Io(_)) => (),
+            _ => panic!("Expected an io error"),
+        }
+    }
+}